@@ -0,0 +1,630 @@
+use lora_mesh::crypto::{self, Keyring};
+use lora_mesh::emulator::AtModule;
+use lora_mesh::identity::{self, Identity};
+use lora_mesh::node::{self, DeliveryState, Message};
+use lora_mesh::outbox::RetryPolicy;
+use lora_mesh::protocol::{
+    self, DecodeError, EncodeError, Frame, Reception, MAX_FRAGMENTS, MAX_PAYLOAD,
+};
+use lora_mesh::reassembly::{Assembly, Reassembler, ReassemblyPolicy};
+use lora_mesh::sim::{Link, SimConfig, Simulator};
+use lora_mesh::transfer::{self, TransferError};
+use std::time::{Duration, Instant};
+
+const SENDER: &str = "002E0051044A7EE1000026C0";
+const RECIPIENT: &str = "002E0051044A7EE1000026BF";
+
+/// Check the length field of the `AT+SEND` for `payload` and that a module takes it.
+fn assert_framed(payload: &str) {
+    let command = protocol::send_command(payload).unwrap();
+    let args = command.strip_prefix("AT+SEND=").unwrap();
+    let mut parts = args.splitn(3, ',');
+    assert_eq!(parts.next(), Some("0"));
+    let length: usize = parts.next().unwrap().parse().unwrap();
+    let data = parts.next().unwrap();
+    assert_eq!(data, payload);
+    assert_eq!(length, payload.len(), "length field of {command:?}");
+
+    let response = AtModule::new(SENDER.to_string()).command(&command);
+    assert_eq!(response.reply, "+OK");
+    assert_eq!(response.transmit.as_deref(), Some(payload));
+}
+
+fn data(body: &str) -> String {
+    protocol::encode(&Frame::Data {
+        id: Some(0x0BADCAFE),
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        next: None,
+        recipient: RECIPIENT.to_string(),
+        sender: SENDER.to_string(),
+        time: 1_700_000_000,
+        body: body.to_string(),
+    })
+}
+
+/// Our message to the recipient, queued to go out.
+fn message(body: &str) -> Message {
+    Message {
+        id: Some(0x0BADCAFE),
+        sender: SENDER.to_string(),
+        recipient: RECIPIENT.to_string(),
+        data: body.to_string(),
+        time: 1_700_000_000,
+        state: DeliveryState::Queued,
+        count: 0,
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        parts: 0,
+        parts_acked: 0,
+    }
+}
+
+/// One frame of every kind, in the current layout and the old one where there is one,
+/// routed and flooded.
+fn every_frame() -> Vec<Frame> {
+    let identity = Identity::generate();
+    let tag = Some(crypto::tag(&[7; 32].into(), "tagged"));
+    let next = Some(RECIPIENT.to_string());
+    let mut frames = Vec::new();
+    for next in [None, next] {
+        frames.push(Frame::Data {
+            id: Some(0x0BADCAFE),
+            ttl: 5,
+            hops: 2,
+            next: next.clone(),
+            recipient: RECIPIENT.to_string(),
+            sender: SENDER.to_string(),
+            time: 1_700_000_000,
+            body: "a, b, c".to_string(),
+        });
+        for tag in [None, tag.clone()] {
+            frames.push(Frame::Confirmation {
+                id: Some(0x0BADCAFE),
+                ttl: 5,
+                hops: 2,
+                next: next.clone(),
+                time: 1_700_000_000,
+                recipient: RECIPIENT.to_string(),
+                sender: SENDER.to_string(),
+                tag: tag.clone(),
+            });
+            frames.push(Frame::FragmentAck {
+                id: 0x0BADCAFE,
+                ttl: 5,
+                hops: 2,
+                next: next.clone(),
+                time: 1_700_000_000,
+                recipient: RECIPIENT.to_string(),
+                sender: SENDER.to_string(),
+                received: 0b1011,
+                tag,
+            });
+        }
+        frames.push(Frame::Fragment {
+            id: 0x0BADCAFE,
+            ttl: 5,
+            hops: 2,
+            next,
+            index: 1,
+            count: 3,
+            recipient: RECIPIENT.to_string(),
+            sender: SENDER.to_string(),
+            time: 1_700_000_000,
+            chunk: "b, c".to_string(),
+        });
+    }
+    frames.extend([
+        Frame::Data {
+            id: None,
+            ttl: 0,
+            hops: 0,
+            next: None,
+            recipient: RECIPIENT.to_string(),
+            sender: SENDER.to_string(),
+            time: 1_700_000_000,
+            body: "a, b, c".to_string(),
+        },
+        Frame::Confirmation {
+            id: None,
+            ttl: 0,
+            hops: 0,
+            next: None,
+            time: 1_700_000_000,
+            recipient: RECIPIENT.to_string(),
+            sender: SENDER.to_string(),
+            tag: None,
+        },
+        Frame::Confirmation {
+            id: None,
+            ttl: 0,
+            hops: 0,
+            next: None,
+            time: 1_700_000_000,
+            recipient: RECIPIENT.to_string(),
+            sender: SENDER.to_string(),
+            tag,
+        },
+        Frame::RouteRequest {
+            id: 0x0BADCAFE,
+            ttl: 5,
+            hops: 2,
+            time: 1_700_000_000,
+            recipient: RECIPIENT.to_string(),
+            sender: SENDER.to_string(),
+            last_hop: RECIPIENT.to_string(),
+        },
+        Frame::RouteReply {
+            id: 0x0BADCAFE,
+            ttl: 5,
+            hops: 2,
+            time: 1_700_000_000,
+            recipient: RECIPIENT.to_string(),
+            sender: SENDER.to_string(),
+            next: RECIPIENT.to_string(),
+            last_hop: SENDER.to_string(),
+        },
+        identity.announcement(SENDER, 1_700_000_000, 5),
+        identity.beacon(SENDER, 1_700_000_000, 5),
+    ]);
+    frames
+}
+
+#[test]
+fn every_frame_round_trips() {
+    for frame in every_frame() {
+        let payload = protocol::encode(&frame);
+        assert_framed(&payload);
+        assert_eq!(protocol::decode(&payload), Ok(frame), "{payload}");
+    }
+}
+
+#[test]
+fn truncated_frames_are_errors() {
+    for frame in every_frame() {
+        let payload = protocol::encode(&frame);
+        // Data frames and fragments end in a body of any length, and the tag of an
+        // ack may be left off
+        let shortest = match &frame {
+            Frame::Data { body, .. } => payload.len() - protocol::escape_body(body).len(),
+            Frame::Fragment { chunk, .. } => payload.len() - chunk.len(),
+            Frame::Confirmation { tag: Some(tag), .. }
+            | Frame::FragmentAck { tag: Some(tag), .. } => payload.len() - tag.len(),
+            _ => payload.len(),
+        };
+        for len in 0..shortest {
+            assert!(
+                protocol::decode(&payload[..len]).is_err(),
+                "{:?}",
+                &payload[..len]
+            );
+        }
+        for len in shortest + 1..payload.len() {
+            let decoded = protocol::decode(&payload[..len]);
+            let ok = matches!(frame, Frame::Data { .. } | Frame::Fragment { .. });
+            assert_eq!(decoded.is_ok(), ok, "{:?}", &payload[..len]);
+        }
+    }
+    assert_eq!(
+        protocol::decode("MSG"),
+        Err(DecodeError::TooShort {
+            expected: 4,
+            found: 3
+        })
+    );
+}
+
+#[test]
+fn malformed_fields_are_errors() {
+    let payload = data("a, b, c");
+    let with = |at: usize, field: &str| {
+        let mut payload = payload.clone();
+        payload.replace_range(at..at + field.len(), field);
+        protocol::decode(&payload)
+    };
+    // MSG ttl hops id recipient sender time
+    assert_eq!(with(3, "G"), Err(DecodeError::InvalidField("ttl")));
+    assert_eq!(with(4, "-"), Err(DecodeError::InvalidField("hops")));
+    assert_eq!(with(5, "0BADCAFZ"), Err(DecodeError::InvalidField("id")));
+    assert_eq!(with(13, "+"), Err(DecodeError::InvalidField("recipient")));
+    assert_eq!(with(37, "G"), Err(DecodeError::InvalidField("sender")));
+    assert_eq!(with(61, "+"), Err(DecodeError::InvalidField("time")));
+    // A character cut in two by a field boundary
+    let split = format!("MSG{}ü{}", "7", &payload[5..]);
+    assert_eq!(
+        protocol::decode(&split),
+        Err(DecodeError::InvalidField("hops"))
+    );
+    assert_eq!(
+        protocol::decode(&format!(">{}{payload}", "Z".repeat(24))),
+        Err(DecodeError::InvalidField("next"))
+    );
+}
+
+#[test]
+fn bytes_after_the_last_field_are_errors() {
+    for frame in every_frame() {
+        let payload = protocol::encode(&frame);
+        let longer = format!("{payload}{}", "A".repeat(23));
+        match frame {
+            // Whatever follows is body
+            Frame::Data { .. } | Frame::Fragment { .. } => {
+                assert!(protocol::decode(&longer).is_ok())
+            }
+            _ => assert!(
+                matches!(
+                    protocol::decode(&longer),
+                    Err(DecodeError::TooLong { .. } | DecodeError::TooShort { .. })
+                ),
+                "{longer}"
+            ),
+        }
+    }
+    let tag = crypto::tag(&[7; 32].into(), "tagged");
+    let mut confirmation = protocol::encode(&Frame::Confirmation {
+        id: Some(0x0BADCAFE),
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        next: None,
+        time: 1_700_000_000,
+        recipient: RECIPIENT.to_string(),
+        sender: SENDER.to_string(),
+        tag: Some(tag),
+    });
+    confirmation.push('X');
+    assert_eq!(
+        protocol::decode(&confirmation),
+        Err(DecodeError::TooLong {
+            expected: confirmation.len() - 1,
+            found: confirmation.len()
+        })
+    );
+}
+
+#[test]
+fn any_body_survives_encoding() {
+    for body in [
+        "",
+        "a, b, c",
+        ",,,",
+        "100%",
+        "%2C is a comma",
+        "%%41",
+        "line one\r\nline two\n",
+        "tab\there",
+        "héllo wörld",
+        "日本語",
+        "🙂👍🏽",
+        "\u{0}\u{7f}",
+    ] {
+        let payload = data(body);
+        assert!(!payload.contains([',', '\r', '\n']), "{payload:?}");
+        assert!(payload.is_ascii(), "{payload:?}");
+        let Ok(Frame::Data { body: decoded, .. }) = protocol::decode(&payload) else {
+            panic!("{payload:?} didn't decode");
+        };
+        assert_eq!(decoded, body);
+    }
+}
+
+#[test]
+fn body_escapes_are_reversed() {
+    assert_eq!(protocol::escape_body("plain text"), "plain text");
+    assert_eq!(protocol::escape_body("a, 100%"), "a%2C 100%25");
+    assert_eq!(protocol::escape_body("\r\n"), "%0D%0A");
+    assert_eq!(protocol::escape_body("é"), "%C3%A9");
+    assert_eq!(protocol::unescape_body("a%2C 100%25"), "a, 100%");
+    assert_eq!(protocol::unescape_body("%c3%a9"), "é");
+
+    // Malformed escapes, as in text from older clients, are kept as they are
+    for literal in ["%", "100%", "%4", "%G1", "%%", "50% off", "%2"] {
+        assert_eq!(protocol::unescape_body(literal), literal);
+    }
+    assert_eq!(protocol::unescape_body("%%41"), "%A");
+    // Bytes that aren't UTF-8 come out as replacement characters
+    assert_eq!(protocol::unescape_body("%FFok"), "\u{FFFD}ok");
+}
+
+#[test]
+fn plain_data_length_matches_payload_bytes() {
+    for body in [
+        "",
+        "hi",
+        "  padded  ",
+        "a, b, c",
+        "100%",
+        "héllo wörld",
+        "🙂",
+    ] {
+        assert_framed(&data(body));
+    }
+}
+
+#[test]
+fn sealed_data_length_matches_payload_bytes() {
+    let mut keyring = Keyring::default();
+    keyring.set_mesh_secret("field team");
+    for body in ["hi", "a, b, c", "héllo wörld"] {
+        let message = message(body);
+        assert_framed(&protocol::encode(&node::data_frame(&keyring, &message)));
+    }
+}
+
+#[test]
+fn control_frame_lengths_match_payload_bytes() {
+    let confirmation = |tag| Frame::Confirmation {
+        id: Some(0x0BADCAFE),
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        next: None,
+        time: 1_700_000_000,
+        recipient: RECIPIENT.to_string(),
+        sender: SENDER.to_string(),
+        tag,
+    };
+    let unsigned = protocol::encode(&confirmation(None));
+    assert_framed(&unsigned);
+    let tag = crypto::tag(&[7; 32].into(), &unsigned);
+    assert_framed(&protocol::encode(&confirmation(Some(tag))));
+
+    let announcement =
+        Identity::generate().announcement(SENDER, 1_700_000_000, protocol::DEFAULT_TTL);
+    assert_framed(&protocol::encode(&announcement));
+}
+
+#[test]
+fn beacon_signature_survives_relaying() {
+    let identity = Identity::generate();
+    let beacon = identity.beacon(SENDER, 1_700_000_000, 2);
+    let payload = protocol::encode(&beacon);
+    assert_framed(&payload);
+    assert_eq!(protocol::decode(&payload), Ok(beacon.clone()));
+
+    let relayed = beacon.relayed(RECIPIENT, None).unwrap();
+    assert_eq!(relayed.hops(), 1);
+    assert!(identity::verify_beacon(&relayed, &identity.public()));
+    assert!(!identity::verify_beacon(
+        &relayed,
+        &Identity::generate().public()
+    ));
+
+    // Moving the time would let an old beacon pass for a new one
+    let mut moved = relayed;
+    if let Frame::Beacon { time, .. } = &mut moved {
+        *time += 60;
+    }
+    assert!(!identity::verify_beacon(&moved, &identity.public()));
+}
+
+#[test]
+fn announcement_signature_survives_relaying() {
+    let identity = Identity::generate();
+    let announcement = identity.announcement(SENDER, 1_700_000_000, 2);
+    let payload = protocol::encode(&announcement);
+    assert_framed(&payload);
+    assert_eq!(protocol::decode(&payload), Ok(announcement.clone()));
+
+    let relayed = announcement.relayed(RECIPIENT, None).unwrap();
+    assert_eq!(relayed.hops(), 1);
+    assert!(identity::verify_announcement(&relayed) == Some(identity.public()));
+    // Out of hops after the second relay
+    let relayed = relayed.relayed(RECIPIENT, None).unwrap();
+    assert!(relayed.relayed(RECIPIENT, None).is_none());
+
+    // Another station's keys can't be passed off under the signature
+    let mut swapped = relayed;
+    if let Frame::Announce { public_key, .. } = &mut swapped {
+        *public_key = Identity::generate().public().encode();
+    }
+    assert!(identity::verify_announcement(&swapped).is_none());
+}
+
+#[test]
+fn oversized_payload_is_refused() {
+    let payload = data(&"x".repeat(MAX_PAYLOAD));
+    assert_eq!(
+        protocol::send_command(&payload),
+        Err(EncodeError::TooLong {
+            length: payload.len()
+        })
+    );
+}
+
+#[test]
+fn sends_retries_and_relays_are_all_accepted_by_the_module() {
+    let mut sim = Simulator::new(SimConfig {
+        range: 10.0,
+        ..Default::default()
+    });
+    sim.add_node((0.0, 0.0));
+    sim.add_node((8.0, 0.0));
+    sim.add_node((16.0, 0.0));
+    sim.node_mut(0).set_retry_policy(RetryPolicy {
+        first_delay: Duration::from_millis(50),
+        max_attempts: 3,
+        write_error_delay: Duration::from_millis(10),
+        frame_gap: Duration::ZERO,
+    });
+    // Nothing gets through the relay, so the message is retried until it fails
+    sim.set_link(
+        1,
+        2,
+        Some(Link {
+            loss: 1.0,
+            latency: Duration::from_millis(10),
+        }),
+    );
+    let recipient = sim.uid(2);
+    sim.node_mut(0)
+        .send_message(&recipient, "a, b, c: 100% ünïcode");
+
+    let failed = sim.run_until(Duration::from_secs(3), |sim| {
+        let messages = sim.node(0).messages();
+        let messages = messages.lock().unwrap();
+        messages[&recipient]
+            .iter()
+            .all(|message| message.state == DeliveryState::Failed)
+    });
+    assert!(failed);
+    // Three sends and a route request; the relay passes on the request and the first
+    // send, and recognises the repeats
+    assert_eq!(sim.transmissions(), 6);
+    assert_eq!(sim.rejected_commands(), 0);
+}
+
+#[test]
+fn long_message_fragments_are_all_framed_and_fit_together() {
+    let mut keyring = Keyring::default();
+    keyring.set_mesh_secret("field team");
+    let body = "a, b, c: 100% ünïcode 🙂 ".repeat(40);
+    let message = message(&body);
+    let fragments = node::message_frames(&keyring, &message).unwrap();
+    assert!(fragments.len() > 1);
+    // Sealed the same way again, so a resent fragment fits with the first ones
+    assert_eq!(
+        node::message_frames(&keyring, &message),
+        Some(fragments.clone())
+    );
+
+    let mut reassembler = Reassembler::default();
+    let now = Instant::now();
+    let mut assembled = None;
+    // Routed and relayed, each is still within a payload and parses back as it was
+    for fragment in fragments.iter().rev() {
+        let relayed = fragment.relayed(SENDER, Some(RECIPIENT.to_string()));
+        let payload = protocol::encode(&relayed.unwrap());
+        assert_framed(&payload);
+        let decoded = protocol::decode(&payload).unwrap();
+        assembled = reassembler.insert(&decoded, now);
+    }
+    let Some(Assembly::Complete(frame)) = assembled else {
+        panic!("fragments did not add up: {assembled:?}");
+    };
+    let Frame::Data { body: sealed, .. } = frame else {
+        unreachable!()
+    };
+    let header = protocol::data_header(message.id, RECIPIENT, SENDER, message.time);
+    let keys = keyring.candidate_keys(RECIPIENT, SENDER);
+    let (text, _) = crypto::open_with_any(&keys, &header, &sealed).unwrap();
+    assert_eq!(text, body);
+}
+
+#[test]
+fn reassembler_keeps_to_its_limits() {
+    let fragment = |sender: &str, id| Frame::Fragment {
+        id,
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        next: None,
+        index: 0,
+        count: 2,
+        recipient: RECIPIENT.to_string(),
+        sender: sender.to_string(),
+        time: 1_700_000_000,
+        chunk: "x".to_string(),
+    };
+    let mut reassembler = Reassembler::default();
+    reassembler.set_policy(ReassemblyPolicy {
+        per_sender: 2,
+        capacity: 3,
+        ..Default::default()
+    });
+    let start = Instant::now();
+    let at = |millis| start + Duration::from_millis(millis);
+    reassembler.insert(&fragment(SENDER, 1), at(0));
+    reassembler.insert(&fragment(SENDER, 2), at(1));
+    // A third from the same sender pushes out its first
+    reassembler.insert(&fragment(SENDER, 3), at(2));
+    let ids = |reassembler: &Reassembler| {
+        let mut ids: Vec<_> = reassembler
+            .in_progress(at(3))
+            .into_iter()
+            .map(|progress| (progress.sender, progress.id))
+            .collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(
+        ids(&reassembler),
+        vec![(SENDER.to_string(), 2), (SENDER.to_string(), 3)]
+    );
+
+    // Past the total, the one heard from longest ago goes, whoever sent it
+    reassembler.insert(&fragment(RECIPIENT, 1), at(3));
+    reassembler.insert(&fragment(RECIPIENT, 2), at(4));
+    assert_eq!(
+        ids(&reassembler),
+        vec![
+            (RECIPIENT.to_string(), 1),
+            (RECIPIENT.to_string(), 2),
+            (SENDER.to_string(), 3)
+        ]
+    );
+}
+
+#[test]
+fn full_file_chunk_fits_one_message_sealed() {
+    let mut keyring = Keyring::default();
+    keyring.set_mesh_secret("field team");
+    let name = format!("{}.log", "ü".repeat(80));
+    let bytes: Vec<u8> = (0..transfer::CHUNK_LEN).map(|byte| byte as u8).collect();
+    let chunks = transfer::split(&name, &bytes).unwrap();
+    assert_eq!(chunks.len(), 1);
+    let message = message(&chunks[0].encode());
+    assert!(node::message_frames(&keyring, &message).is_some());
+    assert_eq!(
+        transfer::Chunk::decode(&message.data),
+        Some(chunks[0].clone())
+    );
+
+    assert_eq!(
+        transfer::split("big.bin", &vec![0; transfer::MAX_FILE_LEN + 1]),
+        Err(TransferError::TooLarge(transfer::MAX_FILE_LEN + 1))
+    );
+    // Nor is a header taken for one, or for more chunks than its size needs
+    let header = |count: u32, size: usize| {
+        format!("\u{1}FILE {} 0 {count} {size} big.bin\n", "0".repeat(64))
+    };
+    let (largest, too_large) = (transfer::MAX_FILE_LEN, transfer::MAX_FILE_LEN + 1);
+    assert!(transfer::header(&header(0, 0)).is_none());
+    assert!(transfer::header(&header(1, 0)).is_some());
+    assert!(transfer::header(&header(32, largest)).is_some());
+    assert!(transfer::header(&header(33, too_large)).is_none());
+    assert!(transfer::header(&header(u32::MAX, 100)).is_none());
+}
+
+#[test]
+fn oversized_message_fails_without_transmitting() {
+    let mut sim = Simulator::new(SimConfig::default());
+    sim.add_node((0.0, 0.0));
+    sim.add_node((1.0, 0.0));
+    let recipient = sim.uid(1);
+    // Too long even when split into as many fragments as an ack can count
+    sim.node_mut(0)
+        .send_message(&recipient, &"x".repeat(MAX_FRAGMENTS * MAX_PAYLOAD));
+
+    let messages = sim.node(0).messages();
+    let state = messages.lock().unwrap()[&recipient][0].state;
+    assert_eq!(state, DeliveryState::Failed);
+    assert_eq!(sim.transmissions(), 0);
+}
+
+#[test]
+fn receive_line_carries_signal_quality() {
+    let payload = data("a, b, c");
+    let line = AtModule::new(SENDER.to_string()).receive(7, &payload, -97, -3);
+    let reception = protocol::receive(&line).unwrap();
+    assert_eq!(
+        reception,
+        Reception {
+            address: 7,
+            payload: &payload,
+            rssi: -97,
+            snr: -3,
+        }
+    );
+    assert_eq!(
+        protocol::receive("+OK"),
+        Err(protocol::DecodeError::NotReceive)
+    );
+}
@@ -0,0 +1,1140 @@
+use lora_mesh::channels::channel_uid;
+use lora_mesh::contacts::AddressBook;
+use lora_mesh::crypto::{self, Keyring};
+use lora_mesh::dedupe::DedupePolicy;
+use lora_mesh::identity::Identity;
+use lora_mesh::mailbox::MailboxPolicy;
+use lora_mesh::node::{self, DeliveryState, Message};
+use lora_mesh::outbox::RetryPolicy;
+use lora_mesh::presence::{Presence, PresencePolicy, PresenceTable};
+use lora_mesh::protocol::{self, Frame};
+use lora_mesh::sim::{Link, SimConfig, Simulator};
+use lora_mesh::transfer::{self, Transfer, TransferError};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+fn received(sim: &Simulator, node: usize, from: usize) -> Vec<String> {
+    let messages = sim.node(node).messages();
+    let messages = messages.lock().unwrap();
+    messages
+        .get(&sim.uid(from))
+        .map(|conversation| {
+            conversation
+                .iter()
+                .filter(|message| message.sender == sim.uid(from))
+                .map(|message| message.data.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn confirmed(sim: &Simulator, node: usize, peer: usize) -> bool {
+    let messages = sim.node(node).messages();
+    let messages = messages.lock().unwrap();
+    messages.get(&sim.uid(peer)).is_some_and(|conversation| {
+        conversation
+            .iter()
+            .all(|message| message.state == DeliveryState::Delivered)
+    })
+}
+
+/// Three stations in a line, the ends out of each other's range.
+fn line() -> Simulator {
+    let mut sim = Simulator::new(SimConfig {
+        range: 10.0,
+        ..Default::default()
+    });
+    sim.add_node((0.0, 0.0));
+    sim.add_node((8.0, 0.0));
+    sim.add_node((16.0, 0.0));
+    sim
+}
+
+#[test]
+fn direct_message_is_delivered_and_confirmed() {
+    let mut sim = line();
+    let recipient = sim.uid(1);
+    sim.node_mut(0).send_message(&recipient, "hello");
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+    assert_eq!(received(&sim, 1, 0), vec!["hello"]);
+}
+
+#[test]
+fn message_is_relayed_to_out_of_range_node() {
+    let mut sim = line();
+    let recipient = sim.uid(2);
+    sim.node_mut(0).send_message(&recipient, "over, the hill");
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+    assert_eq!(received(&sim, 2, 0), vec!["over, the hill"]);
+    assert!(received(&sim, 1, 0).is_empty());
+}
+
+#[test]
+fn duplicate_paths_deliver_once() {
+    let mut sim = diamond();
+    let recipient = sim.uid(3);
+    sim.node_mut(0).send_message(&recipient, "once");
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 3)));
+    sim.run_for(Duration::from_millis(100));
+    assert_eq!(received(&sim, 3, 0), vec!["once"]);
+    assert!(sim.node(3).dedupe_stats().suppressed >= 1);
+}
+
+/// Two paths from 0 to 3, through 1 and through 2.
+fn diamond() -> Simulator {
+    let mut sim = Simulator::new(SimConfig {
+        range: 10.0,
+        ..Default::default()
+    });
+    sim.add_node((0.0, 0.0));
+    sim.add_node((7.0, 7.0));
+    sim.add_node((7.0, -7.0));
+    sim.add_node((14.0, 0.0));
+    sim
+}
+
+#[test]
+fn lossy_link_drops_message() {
+    let mut sim = line();
+    sim.set_link(
+        0,
+        1,
+        Some(Link {
+            loss: 1.0,
+            latency: Duration::from_millis(10),
+        }),
+    );
+    let recipient = sim.uid(1);
+    sim.node_mut(0).send_message(&recipient, "lost");
+
+    sim.run_for(Duration::from_millis(200));
+    assert!(received(&sim, 1, 0).is_empty());
+    assert!(!confirmed(&sim, 0, 1));
+}
+
+#[test]
+fn encrypted_message_is_relayed_and_opened() {
+    let mut sim = line();
+    for node in [0, 2] {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().set_mesh_secret("field team");
+    }
+    let recipient = sim.uid(2);
+    sim.node_mut(0).send_message(&recipient, "sealed");
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+    assert_eq!(received(&sim, 2, 0), vec!["sealed"]);
+}
+
+#[test]
+fn message_under_wrong_key_is_rejected() {
+    let mut sim = line();
+    let keyring = sim.node(0).keyring();
+    keyring.lock().unwrap().set_mesh_secret("field team");
+    let keyring = sim.node(1).keyring();
+    keyring.lock().unwrap().set_mesh_secret("someone else");
+    let recipient = sim.uid(1);
+    sim.node_mut(0).send_message(&recipient, "sealed");
+
+    sim.run_for(Duration::from_millis(200));
+    assert!(received(&sim, 1, 0).is_empty());
+    assert!(!confirmed(&sim, 0, 1));
+}
+
+#[test]
+fn passphrase_forgery_from_pinned_peer_is_rejected() {
+    let mut sim = line();
+    for node in 0..3 {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().set_mesh_secret("field team");
+    }
+    let (first, last) = (sim.uid(0), sim.uid(2));
+    let public = sim.node(0).keyring().lock().unwrap().identity().public();
+    sim.node(2).keyring().lock().unwrap().pin(&first, public);
+
+    // The relay knows the passphrase too, but not the key node 0 pinned with node 2
+    let mut forger = Keyring::default();
+    forger.set_mesh_secret("field team");
+    let key = forger.pair_key(&first, &last).unwrap();
+    let time = node::now();
+    let header = protocol::data_header(Some(9), &last, &first, time);
+    let forged = protocol::encode(&Frame::Data {
+        id: Some(9),
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        next: None,
+        recipient: last.clone(),
+        sender: first.clone(),
+        time,
+        body: crypto::seal(&key, &header, "forged"),
+    });
+    let transport = sim.node(1).transport();
+    node::send_payload(transport.lock().unwrap().as_mut(), &forged).unwrap();
+
+    sim.run_for(Duration::from_millis(200));
+    assert!(received(&sim, 2, 0).is_empty());
+}
+
+#[test]
+fn message_gets_through_when_only_the_recipient_has_pinned_a_key() {
+    let mut sim = line();
+    for node in [0, 2] {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().set_mesh_secret("field team");
+    }
+    sim.node_mut(0).set_retry_policy(quick_retries());
+    forget_quickly(&mut sim);
+    // Node 0 missed node 2's announcement and still seals with the passphrase key
+    let (first, last) = (sim.uid(0), sim.uid(2));
+    let public = sim.node(0).keyring().lock().unwrap().identity().public();
+    sim.node(2).keyring().lock().unwrap().pin(&first, public);
+    sim.node_mut(0).send_message(&last, "sealed");
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+    assert_eq!(received(&sim, 2, 0), vec!["sealed"]);
+    assert!(sim.node(0).keyring().lock().unwrap().is_verified(&last));
+}
+
+#[test]
+fn sealed_message_is_not_shown_to_a_station_without_a_key() {
+    let mut sim = line();
+    let keyring = sim.node(0).keyring();
+    keyring.lock().unwrap().set_mesh_secret("field team");
+    sim.node_mut(0).set_retry_policy(quick_retries());
+    forget_quickly(&mut sim);
+    let recipient = sim.uid(1);
+    sim.node_mut(0).send_message(&recipient, "sealed");
+
+    // Refused at first, then sealed under the pair key once the keys were exchanged
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+    assert_eq!(received(&sim, 1, 0), vec!["sealed"]);
+}
+
+/// Let quick retries past the dedupe caches, as retries after the default delay are.
+fn forget_quickly(sim: &mut Simulator) {
+    for node in 0..3 {
+        sim.node_mut(node).set_dedupe_policy(DedupePolicy {
+            lifetime: Duration::from_millis(50),
+            ..Default::default()
+        });
+    }
+}
+
+#[test]
+fn announcements_exchange_keys_across_relays() {
+    let mut sim = line();
+    sim.node_mut(0).announce().unwrap();
+
+    let (first, last) = (sim.uid(0), sim.uid(2));
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        sim.node(0).keyring().lock().unwrap().is_verified(&last)
+            && sim.node(2).keyring().lock().unwrap().is_verified(&first)
+    }));
+
+    sim.node_mut(0).send_message(&last, "verified");
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+    assert_eq!(received(&sim, 2, 0), vec!["verified"]);
+}
+
+#[test]
+fn forged_confirmation_is_ignored() {
+    let mut sim = line();
+    for node in [0, 2] {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().set_mesh_secret("field team");
+    }
+    // The relay never passes the message on, but claims it arrived
+    sim.set_link(1, 2, None);
+    let (sender, recipient) = (sim.uid(0), sim.uid(2));
+    let id = sim.node_mut(0).send_message(&recipient, "sealed");
+    let time = sim.node(0).messages().lock().unwrap()[&recipient][0].time;
+    let forged = protocol::encode(&Frame::Confirmation {
+        id: Some(id),
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        next: None,
+        time,
+        recipient: sender,
+        sender: recipient,
+        tag: None,
+    });
+    let transport = sim.node(1).transport();
+    node::send_payload(transport.lock().unwrap().as_mut(), &forged).unwrap();
+
+    sim.run_for(Duration::from_millis(200));
+    assert!(!confirmed(&sim, 0, 2));
+}
+
+#[test]
+fn forged_confirmation_does_not_shut_out_the_real_one() {
+    let mut sim = line();
+    for node in [0, 2] {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().set_mesh_secret("field team");
+    }
+    let (sender, recipient) = (sim.uid(0), sim.uid(2));
+    let id = sim.node_mut(0).send_message(&recipient, "sealed");
+    let time = sim.node(0).messages().lock().unwrap()[&recipient][0].time;
+    // Ahead of the real one, over the same relay, with the ID read off the air
+    let forged = protocol::encode(&Frame::Confirmation {
+        id: Some(id),
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        next: None,
+        time,
+        recipient: sender,
+        sender: recipient,
+        tag: Some(crypto::tag(&[7; 32].into(), "forged")),
+    });
+    let transport = sim.node(2).transport();
+    node::send_payload(transport.lock().unwrap().as_mut(), &forged).unwrap();
+
+    // Well before the first retry, so only the first confirmation can do it
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+}
+
+#[test]
+fn address_book_round_trips_and_pins_keys() {
+    let path = std::env::temp_dir().join(format!("lora_mesh_contacts_{}.ron", std::process::id()));
+    let peer = Identity::generate().public();
+    let uid = "002E0051044A7EE1000026BF";
+
+    let mut book = AddressBook::default();
+    let contact = book.entry(uid);
+    contact.nickname = "Base camp".to_string();
+    contact.public_key = Some(peer.encode());
+    book.export(&path).unwrap();
+
+    let mut imported = AddressBook::default();
+    let mut keyring = Keyring::default();
+    assert_eq!(imported.import(&path, &mut keyring).unwrap(), 1);
+    assert_eq!(imported, book);
+    assert_eq!(imported.name(uid), "Base camp");
+    assert!(keyring.peer(uid) == Some(&peer));
+    std::fs::remove_file(path).unwrap();
+}
+
+fn quick_retries() -> RetryPolicy {
+    RetryPolicy {
+        first_delay: Duration::from_millis(100),
+        max_attempts: 3,
+        write_error_delay: Duration::from_millis(10),
+        frame_gap: Duration::ZERO,
+    }
+}
+
+fn state(sim: &Simulator, node: usize, peer: usize) -> Option<DeliveryState> {
+    let messages = sim.node(node).messages();
+    let messages = messages.lock().unwrap();
+    messages
+        .get(&sim.uid(peer))
+        .and_then(|conversation| conversation.last())
+        .map(|message| message.state)
+}
+
+#[test]
+fn message_is_retried_until_confirmed() {
+    let mut sim = line();
+    sim.node_mut(0).set_retry_policy(quick_retries());
+    let lost = Link {
+        loss: 1.0,
+        latency: Duration::from_millis(10),
+    };
+    sim.set_link(0, 1, Some(lost));
+    let recipient = sim.uid(1);
+    sim.node_mut(0).send_message(&recipient, "again");
+    assert_eq!(state(&sim, 0, 1), Some(DeliveryState::Sent));
+
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        state(sim, 0, 1) == Some(DeliveryState::Retrying)
+    }));
+    sim.set_link(
+        0,
+        1,
+        Some(Link {
+            loss: 0.0,
+            latency: Duration::from_millis(10),
+        }),
+    );
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+    assert_eq!(received(&sim, 1, 0), vec!["again"]);
+}
+
+#[test]
+fn message_fails_after_last_retry() {
+    let mut sim = line();
+    sim.node_mut(0).set_retry_policy(quick_retries());
+    let recipient = sim.uid(2);
+    sim.set_link(1, 2, None);
+    sim.node_mut(0).send_message(&recipient, "nobody home");
+
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        state(sim, 0, 2) == Some(DeliveryState::Failed)
+    }));
+    let messages = sim.node(0).messages();
+    assert_eq!(messages.lock().unwrap()[&recipient][0].count, 3);
+}
+
+#[test]
+fn failed_message_can_be_retried() {
+    let mut sim = line();
+    sim.node_mut(0).set_retry_policy(quick_retries());
+    let recipient = sim.uid(2);
+    // Cut off before the relay, which would take a quick retry for a repeat of an
+    // attempt it already passed on
+    sim.set_link(0, 1, None);
+    let id = sim.node_mut(0).send_message(&recipient, "try later");
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        state(sim, 0, 2) == Some(DeliveryState::Failed)
+    }));
+
+    sim.set_link(
+        0,
+        1,
+        Some(Link {
+            loss: 0.0,
+            latency: Duration::from_millis(10),
+        }),
+    );
+    assert!(sim.node_mut(0).retry(&recipient, id));
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+    assert_eq!(received(&sim, 2, 0), vec!["try later"]);
+}
+
+#[test]
+fn messages_in_the_same_second_are_confirmed_separately() {
+    let mut sim = line();
+    let recipient = sim.uid(1);
+    let first = sim.node_mut(0).send_message(&recipient, "same");
+    let second = sim.node_mut(0).send_message(&recipient, "same");
+    assert_ne!(first, second);
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+    assert_eq!(received(&sim, 1, 0), vec!["same", "same"]);
+    let messages = sim.node(1).messages();
+    let ids: Vec<_> = messages.lock().unwrap()[&sim.uid(0)]
+        .iter()
+        .map(|message| message.id)
+        .collect();
+    assert_eq!(ids, vec![Some(first), Some(second)]);
+}
+
+#[test]
+fn frames_from_older_clients_are_still_understood() {
+    let mut sim = line();
+    let (sender, recipient) = (sim.uid(0), sim.uid(1));
+    let time = node::now();
+    // Data without an ID, as sent before IDs were introduced
+    let legacy = protocol::encode(&Frame::Data {
+        id: None,
+        ttl: 0,
+        hops: 0,
+        next: None,
+        recipient: recipient.clone(),
+        sender: sender.clone(),
+        time,
+        body: "from the old days".to_string(),
+    });
+    assert!(legacy.starts_with(&recipient));
+    let transport = sim.node(0).transport();
+    node::send_payload(transport.lock().unwrap().as_mut(), &legacy).unwrap();
+
+    assert!(sim.run_until(TIMEOUT, |sim| !received(sim, 1, 0).is_empty()));
+    assert_eq!(received(&sim, 1, 0), vec!["from the old days"]);
+    let messages = sim.node(1).messages();
+    assert_eq!(messages.lock().unwrap()[&sender][0].id, None);
+    // The confirmation goes back in the old format too
+    let confirmation = protocol::encode(&Frame::Confirmation {
+        id: None,
+        ttl: 0,
+        hops: 0,
+        next: None,
+        time,
+        recipient: sender,
+        sender: recipient,
+        tag: None,
+    });
+    assert!(confirmation.starts_with("CONFIRMED"));
+    assert!(matches!(
+        protocol::decode(&confirmation),
+        Ok(Frame::Confirmation { id: None, .. })
+    ));
+}
+
+#[test]
+fn hop_limit_stops_relaying() {
+    let mut sim = line();
+    for node in [0, 2] {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().set_mesh_secret("field team");
+    }
+    let recipient = sim.uid(2);
+    sim.node_mut(0)
+        .send_message_with_ttl(&recipient, "too far", 0);
+    sim.run_for(Duration::from_millis(500));
+    assert!(received(&sim, 2, 0).is_empty());
+
+    sim.node_mut(0)
+        .send_message_with_ttl(&recipient, "one relay", 1);
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        state(sim, 0, 2) == Some(DeliveryState::Delivered)
+    }));
+    let messages = sim.node(2).messages();
+    let messages = messages.lock().unwrap();
+    let message = &messages[&sim.uid(0)][0];
+    assert_eq!(message.data, "one relay");
+    assert_eq!((message.ttl, message.hops), (1, 1));
+}
+
+#[test]
+fn neighbors_are_stations_heard_directly() {
+    let mut sim = line();
+    let recipient = sim.uid(2);
+    sim.node_mut(0).send_message(&recipient, "who's there");
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+
+    let neighbors = sim.node(1).neighbors();
+    let neighbors = neighbors.lock().unwrap();
+    let sender = neighbors.get(&sim.uid(0)).unwrap();
+    // The message and the route request that went with it
+    assert_eq!(sender.packets, 2);
+    // 8 of 10 units away
+    assert_eq!((sender.rssi, sender.snr), (-104, -4));
+    assert!(neighbors.get(&sim.uid(2)).is_some());
+
+    // Node 0 only ever hears node 2 through the relay
+    let far = sim.node(0).neighbors();
+    assert!(far.lock().unwrap().get(&recipient).is_none());
+    let far = sim.node(2).neighbors();
+    assert!(far.lock().unwrap().get(&sim.uid(0)).is_none());
+}
+
+#[test]
+fn discovered_route_replaces_flooding() {
+    let mut sim = diamond();
+    let recipient = sim.uid(3);
+    sim.node_mut(0).send_message(&recipient, "first");
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 3)
+        && sim.node(0).route_to(&sim.uid(3)).is_some()));
+    let route = sim.node(0).route_to(&recipient).unwrap();
+    assert!(route.next_hop == sim.uid(1) || route.next_hop == sim.uid(2));
+    assert_eq!(route.hops, 1);
+    sim.run_for(Duration::from_millis(100));
+
+    let before = sim.transmissions();
+    sim.node_mut(0).send_message(&recipient, "second");
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 3)));
+    sim.run_for(Duration::from_millis(100));
+    // Message and confirmation, each passed on by one relay only
+    assert_eq!(sim.transmissions() - before, 4);
+    assert_eq!(received(&sim, 3, 0), vec!["first", "second"]);
+}
+
+#[test]
+fn broken_route_falls_back_to_flooding() {
+    let mut sim = diamond();
+    sim.node_mut(0).set_retry_policy(quick_retries());
+    let recipient = sim.uid(3);
+    sim.node_mut(0).send_message(&recipient, "first");
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 3)
+        && sim.node(0).route_to(&sim.uid(3)).is_some()));
+
+    let relay = sim.node(0).route_to(&recipient).unwrap().next_hop;
+    let relay = (1..=2).find(|&node| sim.uid(node) == relay).unwrap();
+    sim.set_link(0, relay, None);
+    sim.node_mut(0).send_message(&recipient, "second");
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 3)));
+    assert_eq!(received(&sim, 3, 0), vec!["first", "second"]);
+    assert_eq!(
+        sim.node(0).messages().lock().unwrap()[&recipient][1].count,
+        2
+    );
+}
+
+/// Turn on the mailbox of the three stations of a [`line`].
+fn hold_messages(sim: &mut Simulator) {
+    for node in 0..3 {
+        sim.node_mut(node).set_mailbox_policy(MailboxPolicy {
+            enabled: true,
+            ..Default::default()
+        });
+    }
+}
+
+#[test]
+fn relay_hands_over_held_message_when_recipient_returns() {
+    let mut sim = line();
+    hold_messages(&mut sim);
+    sim.node_mut(0).set_retry_policy(quick_retries());
+    let recipient = sim.uid(2);
+    sim.set_link(1, 2, None);
+    sim.node_mut(0)
+        .send_message(&recipient, "while you were out");
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        state(sim, 0, 2) == Some(DeliveryState::Failed)
+    }));
+    assert_eq!(sim.node(1).mailbox().len(), 1);
+
+    sim.set_link(
+        1,
+        2,
+        Some(Link {
+            loss: 0.0,
+            latency: Duration::from_millis(10),
+        }),
+    );
+    let relay = sim.uid(1);
+    sim.node_mut(2).send_message(&relay, "back in range");
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+    assert_eq!(received(&sim, 2, 0), vec!["while you were out"]);
+    assert!(sim.node(1).mailbox().is_empty());
+}
+
+#[test]
+fn held_messages_are_handed_over_one_per_gap() {
+    let mut sim = line();
+    hold_messages(&mut sim);
+    let gap = Duration::from_millis(500);
+    sim.node_mut(1).set_retry_policy(RetryPolicy {
+        frame_gap: gap,
+        ..Default::default()
+    });
+    let recipient = sim.uid(2);
+    sim.set_link(1, 2, None);
+    for text in ["one", "two", "three"] {
+        sim.node_mut(0).send_message(&recipient, text);
+    }
+    assert!(sim.run_until(TIMEOUT, |sim| sim.node(1).mailbox().len() == 3));
+
+    sim.set_link(
+        1,
+        2,
+        Some(Link {
+            loss: 0.0,
+            latency: Duration::from_millis(10),
+        }),
+    );
+    let relay = sim.uid(1);
+    sim.node_mut(2).send_message(&relay, "back in range");
+    sim.run_for(gap / 2);
+    assert_eq!(received(&sim, 2, 0).len(), 1);
+    assert!(sim.run_until(TIMEOUT, |sim| received(sim, 2, 0).len() == 3));
+}
+
+#[test]
+fn failed_message_is_resent_when_peer_is_heard() {
+    let mut sim = line();
+    hold_messages(&mut sim);
+    sim.node_mut(0).set_retry_policy(quick_retries());
+    let recipient = sim.uid(1);
+    sim.set_link(0, 1, None);
+    sim.node_mut(0).send_message(&recipient, "call me");
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        state(sim, 0, 1) == Some(DeliveryState::Failed)
+    }));
+
+    sim.set_link(
+        0,
+        1,
+        Some(Link {
+            loss: 0.0,
+            latency: Duration::from_millis(10),
+        }),
+    );
+    let sender = sim.uid(0);
+    sim.node_mut(1).send_message(&sender, "anyone there?");
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        state(sim, 0, 1) == Some(DeliveryState::Delivered) && received(sim, 1, 0) == vec!["call me"]
+    }));
+}
+
+/// Message 42, "from before", that `sender` gave up sending to `recipient` at `time`.
+fn failed_message(sender: &str, recipient: &str, time: u64) -> Message {
+    Message {
+        id: Some(42),
+        sender: sender.to_string(),
+        recipient: recipient.to_string(),
+        data: "from before".to_string(),
+        time,
+        state: DeliveryState::Failed,
+        count: 3,
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        parts: 0,
+        parts_acked: 0,
+    }
+}
+
+#[test]
+fn message_retried_long_after_it_was_sent_is_still_accepted() {
+    let mut sim = line();
+    hold_messages(&mut sim);
+    let recipient = sim.uid(1);
+    sim.node_mut(0).send_message(&recipient, "fresh news");
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+
+    // One that failed twenty minutes ago, older than the newest frame the recipient
+    // has from us by far more than a few minutes
+    let stale = failed_message(&sim.uid(0), &recipient, node::now() - 20 * 60);
+    let messages = sim.node(0).messages();
+    messages
+        .lock()
+        .unwrap()
+        .get_mut(&recipient)
+        .unwrap()
+        .insert(0, stale);
+    assert!(sim.node_mut(0).retry(&recipient, 42));
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+    assert_eq!(received(&sim, 1, 0), vec!["fresh news", "from before"]);
+}
+
+#[test]
+fn forged_frame_leaves_replay_window_alone() {
+    let mut sim = line();
+    for node in [0, 1] {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().set_mesh_secret("field team");
+    }
+    let (sender, recipient) = (sim.uid(0), sim.uid(1));
+    // Dated now under a key nobody holds, it would put anything older than a day
+    // out of the window if it counted
+    let time = node::now();
+    let header = protocol::data_header(Some(9), &recipient, &sender, time);
+    let forged = protocol::encode(&Frame::Data {
+        id: Some(9),
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        next: None,
+        recipient: recipient.clone(),
+        sender: sender.clone(),
+        time,
+        body: crypto::seal(&[7; 32].into(), &header, "forged"),
+    });
+    let transport = sim.node(2).transport();
+    node::send_payload(transport.lock().unwrap().as_mut(), &forged).unwrap();
+    sim.run_for(Duration::from_millis(100));
+
+    let old = failed_message(&sender, &recipient, node::now() - 25 * 3600);
+    let messages = sim.node(0).messages();
+    messages
+        .lock()
+        .unwrap()
+        .entry(recipient.clone())
+        .or_default()
+        .push(old);
+    assert!(sim.node_mut(0).retry(&recipient, 42));
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+    assert_eq!(received(&sim, 1, 0), vec!["from before"]);
+}
+
+#[test]
+fn beacons_mark_peers_online_across_relays() {
+    let mut sim = line();
+    let last = sim.uid(2);
+    let public = sim.node(2).keyring().lock().unwrap().identity().public();
+    sim.node(0).keyring().lock().unwrap().pin(&last, public);
+    let presence = sim.node(0).presence();
+
+    // A beacon for node 2 signed by anyone else counts for nothing
+    let forged = Identity::generate().beacon(&last, node::now(), 0);
+    let transport = sim.node(1).transport();
+    node::send_payload(
+        transport.lock().unwrap().as_mut(),
+        &protocol::encode(&forged),
+    )
+    .unwrap();
+    sim.run_for(Duration::from_millis(100));
+    assert_eq!(presence.lock().unwrap().last_heard(&last), None);
+    assert_eq!(
+        presence.lock().unwrap().status(&last, node::now()),
+        Presence::Offline
+    );
+
+    sim.node_mut(2).send_beacon().unwrap();
+    assert!(sim.run_until(TIMEOUT, |_| {
+        presence.lock().unwrap().status(&last, node::now()) == Presence::Online
+    }));
+    // The relay has no key to check it against, so only passed it on
+    let relay = sim.node(1).presence();
+    assert_eq!(relay.lock().unwrap().last_heard(&last), None);
+}
+
+#[test]
+fn beacons_keep_to_their_interval() {
+    let mut sim = line();
+    let before = sim.transmissions();
+    sim.node_mut(0).beacon_if_due();
+    sim.node_mut(0).beacon_if_due();
+    assert_eq!(sim.transmissions() - before, 1);
+
+    sim.node_mut(1).set_presence_policy(PresencePolicy {
+        interval_secs: 0,
+        ..Default::default()
+    });
+    sim.node_mut(1).beacon_if_due();
+    assert_eq!(sim.transmissions() - before, 1);
+}
+
+#[test]
+fn presence_goes_idle_then_offline() {
+    let uid = "002E0051044A7EE1000026BF";
+    let mut presence = PresenceTable::default();
+    presence.set_policy(PresencePolicy {
+        idle_after_secs: 60,
+        offline_after_secs: 300,
+        ..Default::default()
+    });
+    let now = 1_700_000_000;
+    assert!(presence.heard(uid, now, now));
+    assert_eq!(presence.status(uid, now + 60), Presence::Online);
+    assert_eq!(presence.status(uid, now + 61), Presence::Idle);
+    assert_eq!(presence.status(uid, now + 301), Presence::Offline);
+
+    // Played back, the same beacon or an older one changes nothing
+    assert!(!presence.heard(uid, now, now + 120));
+    assert!(!presence.heard(uid, now - 10, now + 120));
+    assert_eq!(presence.last_heard(uid), Some(now));
+    // Nor does one too old to say anything about now
+    assert!(!presence.heard("002E0051044A7EE1000026C0", now, now + 301));
+    assert!(presence.heard(uid, now + 120, now + 121));
+    assert_eq!(presence.status(uid, now + 180), Presence::Online);
+}
+
+/// Bodies of the messages from others in `channel`, as node `node` has them.
+fn channel_messages(sim: &Simulator, node: usize, channel: &str) -> Vec<String> {
+    let messages = sim.node(node).messages();
+    let messages = messages.lock().unwrap();
+    messages
+        .get(channel)
+        .map(|conversation| {
+            conversation
+                .iter()
+                .filter(|message| message.sender != sim.uid(node))
+                .map(|message| message.data.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[test]
+fn broadcast_reaches_every_station_once() {
+    let mut sim = line();
+    sim.node_mut(0)
+        .send_message(protocol::BROADCAST, "all hands");
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        channel_messages(sim, 2, protocol::BROADCAST) == vec!["all hands"]
+    }));
+    sim.run_for(Duration::from_millis(100));
+    assert_eq!(
+        channel_messages(&sim, 1, protocol::BROADCAST),
+        vec!["all hands"]
+    );
+    // Sent once and passed on by each station, with nothing to confirm it
+    assert_eq!(sim.transmissions(), 3);
+    let messages = sim.node(0).messages();
+    let sent = &messages.lock().unwrap()[protocol::BROADCAST][0];
+    assert_eq!((sent.state, sent.count), (DeliveryState::Delivered, 1));
+}
+
+#[test]
+fn group_channel_is_sealed_for_its_members() {
+    let mut sim = line();
+    let ops = channel_uid("ops");
+    for node in [0, 2] {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().join_channel(&ops, "night shift");
+    }
+    // The relay is in a channel of the same name, but under another passphrase
+    let keyring = sim.node(1).keyring();
+    keyring.lock().unwrap().join_channel(&ops, "day shift");
+
+    sim.node_mut(0).send_message(&ops, "meet at the tower");
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        channel_messages(sim, 2, &ops) == vec!["meet at the tower"]
+    }));
+    assert!(channel_messages(&sim, 1, &ops).is_empty());
+    assert!(received(&sim, 2, 0).is_empty());
+}
+
+#[test]
+fn long_message_is_split_relayed_and_reassembled() {
+    let mut sim = line();
+    let recipient = sim.uid(2);
+    let body = "over, the hill: 100% ".repeat(50);
+    sim.node_mut(0).send_message(&recipient, &body);
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+    assert_eq!(received(&sim, 2, 0), vec![body]);
+    assert!(received(&sim, 1, 0).is_empty());
+    assert!(sim.node(2).incoming().is_empty());
+    let messages = sim.node(0).messages();
+    let sent = &messages.lock().unwrap()[&recipient][0];
+    assert!(sent.parts > 1);
+    assert_eq!(sent.parts_acked, sent.parts);
+}
+
+#[test]
+fn fragments_go_out_one_at_a_time_with_a_gap() {
+    let mut sim = line();
+    let gap = Duration::from_millis(50);
+    sim.node_mut(0).set_retry_policy(RetryPolicy {
+        frame_gap: gap,
+        ..Default::default()
+    });
+    let recipient = sim.uid(1);
+    sim.node_mut(0)
+        .send_message(&recipient, &"over, the hill: 100% ".repeat(50));
+    assert_eq!(sim.transmissions(), 1);
+    // The module is still busy with the first
+    sim.node_mut(0).flush_outbox();
+    assert_eq!(sim.transmissions(), 1);
+
+    std::thread::sleep(gap);
+    sim.node_mut(0).flush_outbox();
+    assert_eq!(sim.transmissions(), 2);
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+}
+
+#[test]
+fn lost_fragments_are_resent_until_the_message_is_whole() {
+    let mut sim = line();
+    sim.node_mut(0).set_retry_policy(RetryPolicy {
+        max_attempts: 10,
+        ..quick_retries()
+    });
+    sim.set_link(
+        0,
+        1,
+        Some(Link {
+            loss: 0.4,
+            latency: Duration::from_millis(10),
+        }),
+    );
+    // Fragments resent because their ack was lost are acked again, not taken for echoes
+    sim.node_mut(1).set_dedupe_policy(DedupePolicy {
+        lifetime: Duration::from_millis(50),
+        ..Default::default()
+    });
+    let recipient = sim.uid(1);
+    let body = "x".repeat(1000);
+    sim.node_mut(0).send_message(&recipient, &body);
+
+    // Part of it arrives first and shows as coming in
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        sim.node(1)
+            .incoming()
+            .iter()
+            .any(|progress| progress.received > 0 && progress.received < progress.count)
+    }));
+    assert!(sim.run_until(Duration::from_secs(10), |sim| confirmed(sim, 0, 1)));
+    assert_eq!(received(&sim, 1, 0), vec![body]);
+}
+
+/// A sender with its recipient to one side and a third station to the other, out of
+/// the recipient's range, all sharing the passphrase.
+fn fork() -> Simulator {
+    let mut sim = Simulator::new(SimConfig {
+        range: 10.0,
+        ..Default::default()
+    });
+    sim.add_node((0.0, 0.0));
+    sim.add_node((8.0, 0.0));
+    sim.add_node((-8.0, 0.0));
+    for node in 0..3 {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().set_mesh_secret("field team");
+    }
+    sim
+}
+
+/// Have node 2 send node 0 an ack from node 1 for fragments `received` of node 0's
+/// message to it, tagged with the passphrase key if `tagged`.
+fn ack_fragments(sim: &Simulator, received: u64, tagged: bool) {
+    let (sender, recipient) = (sim.uid(0), sim.uid(1));
+    let (id, time) = {
+        let messages = sim.node(0).messages();
+        let messages = messages.lock().unwrap();
+        let message = &messages[&recipient][0];
+        (message.id.unwrap(), message.time)
+    };
+    let mut ack = Frame::FragmentAck {
+        id,
+        ttl: protocol::DEFAULT_TTL,
+        hops: 0,
+        next: None,
+        time,
+        recipient: sender.clone(),
+        sender: recipient.clone(),
+        received,
+        tag: None,
+    };
+    if tagged {
+        let mut keyring = Keyring::default();
+        keyring.set_mesh_secret("field team");
+        let key = keyring.candidate_keys(&recipient, &sender)[0];
+        let tag = crypto::tag(&key, &protocol::encode(&ack));
+        if let Frame::FragmentAck { tag: field, .. } = &mut ack {
+            *field = Some(tag);
+        }
+    }
+    let transport = sim.node(2).transport();
+    node::send_payload(transport.lock().unwrap().as_mut(), &protocol::encode(&ack)).unwrap();
+}
+
+#[test]
+fn forged_fragment_ack_is_ignored() {
+    let mut sim = fork();
+    // Nothing gets through, but another station claims most of it did
+    sim.set_link(0, 1, None);
+    let recipient = sim.uid(1);
+    sim.node_mut(0)
+        .send_message(&recipient, &"over, the hill: 100% ".repeat(50));
+    ack_fragments(&sim, 0b1, false);
+
+    sim.run_for(Duration::from_millis(100));
+    let messages = sim.node(0).messages();
+    assert_eq!(messages.lock().unwrap()[&recipient][0].parts_acked, 0);
+}
+
+#[test]
+fn retry_sends_every_fragment_again() {
+    let mut sim = fork();
+    sim.node_mut(0).set_retry_policy(quick_retries());
+    forget_quickly(&mut sim);
+    sim.set_link(0, 1, None);
+    let recipient = sim.uid(1);
+    let body = "over, the hill: 100% ".repeat(50);
+    sim.node_mut(0).send_message(&recipient, &body);
+    sim.run_for(Duration::from_millis(50));
+    let parts = sim.node(0).messages().lock().unwrap()[&recipient][0].parts;
+    assert!(parts > 1);
+    // The recipient said it had all but the last, then lost them, e.g. to a restart
+    ack_fragments(&sim, u64::MAX >> (65 - parts), true);
+    sim.run_for(Duration::from_millis(50));
+    sim.set_link(
+        0,
+        1,
+        Some(Link {
+            loss: 0.0,
+            latency: Duration::from_millis(10),
+        }),
+    );
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+    assert_eq!(received(&sim, 1, 0), vec![body]);
+}
+
+#[test]
+fn long_broadcast_is_reassembled_by_every_station() {
+    let mut sim = line();
+    let body = "all hands, ".repeat(60);
+    sim.node_mut(0).send_message(protocol::BROADCAST, &body);
+
+    assert!(sim.run_until(TIMEOUT, |sim| {
+        channel_messages(sim, 2, protocol::BROADCAST) == vec![body.clone()]
+    }));
+    assert_eq!(channel_messages(&sim, 1, protocol::BROADCAST), vec![body]);
+}
+
+/// The files in node `node`'s conversation with `peer`.
+fn transfers(sim: &Simulator, node: usize, peer: usize) -> Vec<Transfer> {
+    let messages = sim.node(node).messages();
+    let messages = messages.lock().unwrap();
+    let conversation = messages.get(&sim.uid(peer)).map_or(&[][..], Vec::as_slice);
+    transfer::transfers(conversation, &sim.uid(node))
+}
+
+#[test]
+fn file_is_sent_in_chunks_and_checked_on_arrival() {
+    let mut sim = line();
+    for node in [0, 2] {
+        let keyring = sim.node(node).keyring();
+        keyring.lock().unwrap().set_mesh_secret("field team");
+    }
+    let bytes: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let recipient = sim.uid(2);
+    let ids = sim
+        .node_mut(0)
+        .send_file(&recipient, "/var/log/sensor.log", &bytes)
+        .unwrap();
+    assert_eq!(ids.len(), 3);
+    // Queued for the outbox to send at its pace, not sent by the caller
+    assert_eq!(sim.transmissions(), 0);
+
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 2)));
+    let received = transfers(&sim, 2, 0);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].file.name, "sensor.log");
+    assert!(received[0].complete());
+    assert!(transfers(&sim, 0, 2)[0].complete());
+
+    let messages = sim.node(2).messages();
+    let mut messages = messages.lock().unwrap();
+    let conversation = messages.get_mut(&sim.uid(0)).unwrap();
+    let file = &received[0].file;
+    assert_eq!(
+        transfer::assemble(conversation, &sim.uid(0), file),
+        Ok(bytes)
+    );
+    // A chunk damaged on the way doesn't make it into the saved file
+    let chunk = &mut conversation[1].data;
+    let at = chunk.find('\n').unwrap() + 10;
+    let damaged = if &chunk[at..at + 1] == "A" { "B" } else { "A" };
+    chunk.replace_range(at..at + 1, damaged);
+    assert_eq!(
+        transfer::assemble(conversation, &sim.uid(0), file),
+        Err(TransferError::Checksum)
+    );
+}
+
+#[test]
+fn interrupted_file_transfer_resumes_with_the_missing_chunks() {
+    let mut sim = line();
+    sim.node_mut(0).set_retry_policy(RetryPolicy {
+        max_attempts: 1,
+        ..quick_retries()
+    });
+    let bytes = vec![0x5A; 3 * transfer::CHUNK_LEN];
+    let recipient = sim.uid(1);
+    sim.set_link(0, 1, None);
+    let ids = sim
+        .node_mut(0)
+        .send_file(&recipient, "thumbnail.jpg", &bytes)
+        .unwrap();
+    assert!(sim.run_until(TIMEOUT, |sim| transfers(sim, 0, 1)[0].failed.len() == 3));
+
+    // The link is back for long enough to get one chunk through
+    sim.set_link(
+        0,
+        1,
+        Some(Link {
+            loss: 0.0,
+            latency: Duration::from_millis(10),
+        }),
+    );
+    assert!(sim.node_mut(0).retry(&recipient, ids[0]));
+    assert!(sim.run_until(TIMEOUT, |sim| transfers(sim, 0, 1)[0].done == 1));
+    assert_eq!(transfers(&sim, 1, 0)[0].done, 1);
+
+    // Resuming sends only the chunks still missing
+    let failed = transfers(&sim, 0, 1)[0].failed.clone();
+    assert_eq!(failed, ids[1..]);
+    for id in failed {
+        assert!(sim.node_mut(0).retry(&recipient, id));
+    }
+    assert!(sim.run_until(TIMEOUT, |sim| confirmed(sim, 0, 1)));
+    let received = transfers(&sim, 1, 0);
+    assert!(received[0].complete());
+    let messages = sim.node(1).messages();
+    let messages = messages.lock().unwrap();
+    assert_eq!(
+        transfer::assemble(&messages[&sim.uid(0)], &sim.uid(0), &received[0].file),
+        Ok(bytes)
+    );
+}
@@ -1,16 +1,18 @@
-use serialport::{self, SerialPort};
-use std::collections::HashMap;
+use crate::channels::Channel;
+use crate::contacts::AddressBook;
+use crate::crypto::{Keyring, SharedKeyring};
+use crate::history::{Retention, SharedHistory};
+use crate::mailbox::MailboxPolicy;
+use crate::neighbors::SharedNeighbors;
+use crate::node::{self, DeliveryState, Message, Node, SharedMessages};
+use crate::presence::{Presence, PresencePolicy, SharedPresence};
+use crate::protocol::{self, MessageId};
+use crate::storage;
+use crate::transfer::{self, FileInfo, Transfer};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
-#[derive(Debug)]
-pub struct Message {
-    pub sender: String,
-    pub recipient: String,
-    pub data: String, // Message Contents
-    pub time: u64,    // UNIX Epoch time
-    pub confirmed: bool,
-    pub count: u64,
-}
 
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -20,42 +22,136 @@ pub struct TemplateApp {
     #[serde(skip)]
     label: String,
     #[serde(skip)]
-    shared_messages: Arc<Mutex<HashMap<String, Vec<Message>>>>,
+    node: Option<Arc<Mutex<Node>>>,
     #[serde(skip)]
-    port: Arc<Mutex<Option<Box<dyn SerialPort>>>>,
+    shared_messages: SharedMessages,
     #[serde(skip)]
     userid: Option<String>,
+    /// The conversation shown in the central panel
+    target_user: Option<String>,
+    /// Nicknames and notes by UID; every entry is listed even before any message
+    address_book: AddressBook,
+    /// Time of the newest message read in each conversation
+    last_read: HashMap<String, u64>,
+    #[serde(skip)]
+    new_contact: String,
+    /// Group conversations we joined
+    channels: Vec<Channel>,
+    #[serde(skip)]
+    new_channel: Channel,
+    #[serde(skip)]
+    show_contact: bool,
+    #[serde(skip)]
+    show_address_book: bool,
+    /// File the address book was last imported from or exported to
+    address_book_path: String,
+    #[serde(skip)]
+    address_book_status: String,
+    #[serde(skip)]
+    show_send_file: bool,
+    /// File last sent
+    file_path: String,
+    #[serde(skip)]
+    file_status: String,
+    /// The received file the save dialog is open for
+    #[serde(skip)]
+    save_file: Option<SaveFile>,
+    #[serde(skip)]
+    keyring: SharedKeyring,
+    /// Passphrase every peer's message key is derived from, empty for plaintext
+    mesh_secret: String,
+    /// How much message history to keep on disk
+    retention: Retention,
+    /// Whether to hold messages for stations out of reach
+    mailbox: MailboxPolicy,
+    /// How often we send beacons and how long peers count as online
+    presence: PresencePolicy,
+    /// How many relays may pass on the next message sent
+    ttl: u8,
+    #[serde(skip)]
+    history: Option<SharedHistory>,
+    #[serde(skip)]
+    show_settings: bool,
+    /// Failed messages, by peer and time, that the user has already been told about
+    #[serde(skip)]
+    reported_failures: HashSet<(String, MessageId, u64)>,
     #[serde(skip)]
-    target_user: Arc<Mutex<Option<String>>>,
+    notifications: Vec<String>,
+    #[serde(skip)]
+    neighbors: SharedNeighbors,
+    #[serde(skip)]
+    last_heard: SharedPresence,
+    /// Whether the neighbors panel is open
+    show_neighbors: bool,
 }
 
 impl Default for TemplateApp {
     fn default() -> Self {
         Self {
             label: String::new(),
+            node: None,
             shared_messages: Arc::new(Mutex::new(HashMap::new())),
-            port: Arc::new(Mutex::new(None)),
             userid: None,
-            target_user: Arc::new(Mutex::new(None)),
+            target_user: None,
+            address_book: AddressBook::default(),
+            last_read: HashMap::new(),
+            new_contact: String::new(),
+            channels: Vec::new(),
+            new_channel: Channel::default(),
+            show_contact: false,
+            show_address_book: false,
+            address_book_path: storage::data_file("address_book.ron")
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            address_book_status: String::new(),
+            show_send_file: false,
+            file_path: String::new(),
+            file_status: String::new(),
+            save_file: None,
+            keyring: Arc::new(Mutex::new(Keyring::default())),
+            mesh_secret: String::new(),
+            retention: Retention::default(),
+            mailbox: MailboxPolicy::default(),
+            presence: PresencePolicy::default(),
+            ttl: protocol::DEFAULT_TTL,
+            history: None,
+            show_settings: false,
+            reported_failures: HashSet::new(),
+            notifications: Vec::new(),
+            neighbors: SharedNeighbors::default(),
+            last_heard: SharedPresence::default(),
+            show_neighbors: false,
         }
     }
 }
 
 impl TemplateApp {
-    pub fn new(
-        cc: &eframe::CreationContext<'_>,
-        shared_messages: Arc<Mutex<HashMap<String, Vec<Message>>>>,
-        serial_port: Arc<Mutex<Option<Box<dyn SerialPort>>>>,
-        userid: Option<String>,
-        target_user: Arc<Mutex<Option<String>>>,
-    ) -> Self {
+    pub fn new(cc: &eframe::CreationContext<'_>, node: Arc<Mutex<Node>>) -> Self {
         if let Some(storage) = cc.storage {
             let mut app: Self = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
             // Update the app with the shared messages after loading
-            app.shared_messages = shared_messages.clone();
-            app.port = serial_port.clone();
-            app.userid = userid.clone();
-            app.target_user = target_user.clone();
+            {
+                let mut node = node.lock().unwrap();
+                app.shared_messages = node.messages();
+                app.userid = Some(node.userid().to_string());
+                app.keyring = node.keyring();
+                app.history = node.history();
+                app.neighbors = node.neighbors();
+                app.last_heard = node.presence();
+                node.set_mailbox_policy(app.mailbox);
+                node.set_presence_policy(app.presence);
+            }
+            app.apply_retention();
+            // Only failures from now on are news
+            app.reported_failures = app.failures();
+            {
+                let mut keyring = app.keyring.lock().unwrap();
+                keyring.set_mesh_secret(&app.mesh_secret);
+                for channel in &app.channels {
+                    keyring.join_channel(&channel.uid(), &channel.secret);
+                }
+            }
+            app.node = Some(node);
 
             return app;
         }
@@ -63,53 +159,229 @@ impl TemplateApp {
         Default::default()
     }
 
-    fn send_message(&self, input: &str) {
-        let binding = self.target_user.lock().unwrap();
-        let recipient = binding.as_ref().unwrap();
-        let sender = self.userid.as_ref().unwrap();
-        let time_stamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
+    /// Send `input` to the open conversation. Returns whether it went out, so the
+    /// draft is kept otherwise.
+    fn send_message(&self, input: &str) -> bool {
+        let input = input.trim();
+        let (Some(recipient), Some(node)) = (&self.target_user, &self.node) else {
+            return false;
+        };
+        if input.is_empty() {
+            return false;
+        }
+        node.lock()
             .unwrap()
-            .as_secs();
-        let command = format!(
-            "AT+SEND=0,{},{}{}{}{}\r\n",
-            input.trim().len() + 58,
-            recipient,
-            sender,
-            time_stamp,
-            input.trim()
-        );
+            .send_message_with_ttl(recipient, input, self.ttl);
+        true
+    }
 
-        match self.port.lock() {
-            Ok(mut port_option) => {
-                if let Some(port) = port_option.as_mut() {
-                    if let Err(_) = port.write(command.as_bytes()) {
-                        eprintln!("Error writing to port");
-                    } else {
-                        let mut messages = self.shared_messages.lock().unwrap();
-                        let messages_vec = messages.entry(recipient.to_string()).or_insert(vec![]);
-                        messages_vec.push(Message {
-                            sender: sender.to_string(),
-                            recipient: recipient.to_string(),
-                            data: input.trim().to_string(),
-                            time: time_stamp,
-                            confirmed: false,
-                            count: 1,
-                        });
-                    }
-                }
+    /// Every one of our messages that gave up, by peer, ID and time.
+    fn failures(&self) -> HashSet<(String, MessageId, u64)> {
+        let messages = self.shared_messages.lock().unwrap();
+        messages
+            .iter()
+            .flat_map(|(peer, conversation)| {
+                conversation
+                    .iter()
+                    .filter(|message| message.state == DeliveryState::Failed)
+                    .filter_map(move |message| Some((peer.clone(), message.id?, message.time)))
+            })
+            .collect()
+    }
+
+    /// Turn messages that failed since the last frame into notifications.
+    fn report_failures(&mut self, ctx: &egui::Context) {
+        let failures = self.failures();
+        for failure @ (peer, _, time) in &failures {
+            if !self.reported_failures.contains(failure) {
+                self.notifications.push(format!(
+                    "Message to {} sent at {} was not delivered",
+                    self.name(peer),
+                    clock_time(*time)
+                ));
+                ctx.send_viewport_cmd(egui::ViewportCommand::RequestUserAttention(
+                    egui::UserAttentionType::Informational,
+                ));
             }
-            Err(_) => {
-                println!("Error locking the port");
+        }
+        // Forget the ones retried since, so a second failure is reported too
+        self.reported_failures = failures;
+    }
+
+    fn retry(&self, peer: &str, id: MessageId) {
+        if let Some(node) = &self.node {
+            node.lock().unwrap().retry(peer, id);
+        }
+    }
+
+    fn apply_retention(&self) {
+        if let Some(history) = &self.history {
+            let mut messages = self.shared_messages.lock().unwrap();
+            history
+                .lock()
+                .unwrap()
+                .set_retention(self.retention, &mut messages);
+        }
+    }
+
+    /// What to call `uid`: the channel name, the contact's nickname, or the UID itself.
+    fn name<'a>(&'a self, uid: &'a str) -> &'a str {
+        if uid == protocol::BROADCAST {
+            return "Everyone";
+        }
+        match self.channels.iter().find(|channel| channel.uid() == uid) {
+            Some(channel) => &channel.name,
+            None => self.address_book.name(uid),
+        }
+    }
+
+    /// Whether `uid` is the broadcast address or one of our channels.
+    fn is_channel(&self, uid: &str) -> bool {
+        uid == protocol::BROADCAST || self.channels.iter().any(|channel| channel.uid() == uid)
+    }
+
+    /// Everyone we have a conversation with or added by hand, and every channel, most
+    /// recently active first.
+    fn conversations(&self) -> Vec<(String, usize)> {
+        let messages = self.shared_messages.lock().unwrap();
+        let own = self.userid.as_deref().unwrap_or_default();
+        let mut peers: Vec<(String, u64, usize)> = messages
+            .iter()
+            .map(|(peer, conversation)| {
+                let latest = conversation.iter().map(|message| message.time).max();
+                let read = self.last_read.get(peer).copied().unwrap_or_default();
+                let unread = conversation
+                    .iter()
+                    .filter(|message| message.sender != own && message.time > read)
+                    .count();
+                (peer.clone(), latest.unwrap_or_default(), unread)
+            })
+            .collect();
+        let channels = self.channels.iter().map(Channel::uid);
+        let channels = std::iter::once(protocol::BROADCAST.to_string()).chain(channels);
+        for uid in channels.chain(self.address_book.uids().cloned()) {
+            if !messages.contains_key(&uid) && !peers.iter().any(|(peer, ..)| *peer == uid) {
+                peers.push((uid, 0, 0));
             }
         }
+        peers.sort_by(|a, b| {
+            b.1.cmp(&a.1).then_with(|| {
+                let names = (self.name(&a.0), self.name(&b.0));
+                names.0.cmp(names.1)
+            })
+        });
+        peers
+            .into_iter()
+            .map(|(peer, _, unread)| (peer, unread))
+            .collect()
+    }
+
+    fn add_contact(&mut self) {
+        let uid = self.new_contact.trim().to_ascii_uppercase();
+        if !protocol::is_uid(&uid) {
+            return;
+        }
+        self.address_book.entry(&uid);
+        self.target_user = Some(uid);
+        self.new_contact.clear();
+    }
+
+    fn join_channel(&mut self) {
+        let name = self.new_channel.name.trim().to_string();
+        if name.is_empty() {
+            return;
+        }
+        let channel = Channel {
+            name,
+            secret: std::mem::take(&mut self.new_channel.secret),
+        };
+        let uid = channel.uid();
+        self.keyring
+            .lock()
+            .unwrap()
+            .join_channel(&uid, &channel.secret);
+        self.channels.retain(|joined| joined.uid() != uid);
+        self.channels.push(channel);
+        self.target_user = Some(uid);
+        self.new_channel.name.clear();
+    }
+
+    fn leave_channel(&mut self, uid: &str) {
+        self.keyring.lock().unwrap().leave_channel(uid);
+        self.channels.retain(|channel| channel.uid() != uid);
+        self.target_user = None;
+    }
+
+    fn import_address_book(&mut self) {
+        let path = std::path::PathBuf::from(self.address_book_path.trim());
+        let imported = self
+            .address_book
+            .import(&path, &mut self.keyring.lock().unwrap());
+        self.address_book_status = match imported {
+            Ok(count) => format!("Imported {} contacts", count),
+            Err(err) => format!("Import failed: {}", err),
+        };
+    }
+
+    fn send_file(&mut self) {
+        let (Some(recipient), Some(node)) = (&self.target_user, &self.node) else {
+            return;
+        };
+        let path = PathBuf::from(self.file_path.trim());
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let sent = fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| {
+                node.lock()
+                    .unwrap()
+                    .send_file_with_ttl(recipient, &name, &bytes, self.ttl)
+                    .map_err(|err| err.to_string())
+            });
+        self.file_status = match sent {
+            Ok(ids) => format!("Sending {} in {} parts", name, ids.len()),
+            Err(err) => format!("Send failed: {}", err),
+        };
+    }
+
+    /// Put the file the save dialog is open for together and write it where chosen.
+    fn save_received_file(&self, save: &SaveFile) -> String {
+        let assembled = {
+            let messages = self.shared_messages.lock().unwrap();
+            let conversation = messages.get(&save.peer).map_or(&[][..], Vec::as_slice);
+            transfer::assemble(conversation, &save.sender, &save.file)
+        };
+        let path = PathBuf::from(save.path.trim());
+        let saved = assembled
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| fs::write(&path, bytes).map_err(|err| err.to_string()));
+        match saved {
+            Ok(()) => format!("Saved to {}", path.display()),
+            Err(err) => format!("Save failed: {}", err),
+        }
+    }
+
+    fn export_address_book(&mut self) {
+        self.address_book.record_keys(&self.keyring.lock().unwrap());
+        let path = std::path::PathBuf::from(self.address_book_path.trim());
+        self.address_book_status = match self.address_book.export(&path) {
+            Ok(()) => format!("Exported to {}", path.display()),
+            Err(err) => format!("Export failed: {}", err),
+        };
     }
 }
 
 impl eframe::App for TemplateApp {
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        self.address_book.record_keys(&self.keyring.lock().unwrap());
         eframe::set_value(storage, eframe::APP_KEY, self);
+        // The node outlives the window, held by its threads until the process ends
+        if let Some(node) = &self.node {
+            node.lock().unwrap().flush_replay();
+        }
     }
 
     /// Called each time the UI needs repainting, which may be many times per second.
@@ -117,6 +389,8 @@ impl eframe::App for TemplateApp {
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
 
+        self.report_failures(ctx);
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
 
@@ -125,6 +399,15 @@ impl eframe::App for TemplateApp {
                 let is_web = cfg!(target_arch = "wasm32");
                 if !is_web {
                     ui.menu_button("File", |ui| {
+                        if ui.button("Settings").clicked() {
+                            self.show_settings = true;
+                            ui.close_menu();
+                        }
+                        if ui.button("Address book").clicked() {
+                            self.show_address_book = true;
+                            ui.close_menu();
+                        }
+                        ui.checkbox(&mut self.show_neighbors, "Neighbors");
                         if ui.button("Quit").clicked() {
                             ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                         }
@@ -134,34 +417,509 @@ impl eframe::App for TemplateApp {
             });
         });
 
+        let mut show_settings = self.show_settings;
+        egui::Window::new("Settings")
+            .open(&mut show_settings)
+            .show(ctx, |ui| {
+                ui.label("Mesh passphrase (leave empty to send in the clear)");
+                let passphrase =
+                    ui.add(egui::TextEdit::singleline(&mut self.mesh_secret).password(true));
+                if passphrase.changed() {
+                    self.keyring
+                        .lock()
+                        .unwrap()
+                        .set_mesh_secret(&self.mesh_secret);
+                }
+                ui.separator();
+                ui.label("Message history (0 keeps everything)");
+                let days =
+                    ui.add(egui::DragValue::new(&mut self.retention.max_age_days).suffix(" days"));
+                let count = ui.add(
+                    egui::DragValue::new(&mut self.retention.max_per_conversation)
+                        .suffix(" messages per conversation"),
+                );
+                if days.lost_focus()
+                    || days.drag_released()
+                    || count.lost_focus()
+                    || count.drag_released()
+                {
+                    self.apply_retention();
+                }
+                ui.separator();
+                let fingerprint = self
+                    .keyring
+                    .lock()
+                    .unwrap()
+                    .identity()
+                    .public()
+                    .fingerprint();
+                ui.label(format!("Your key fingerprint: {}", fingerprint));
+                if ui.button("Announce key").clicked() {
+                    if let Some(node) = &self.node {
+                        if node.lock().unwrap().announce().is_err() {
+                            eprintln!("Error writing to port");
+                        }
+                    }
+                }
+                ui.separator();
+                let mut changed = ui
+                    .checkbox(
+                        &mut self.mailbox.enabled,
+                        "Hold messages for stations out of reach",
+                    )
+                    .changed();
+                let quota = ui.add(
+                    egui::DragValue::new(&mut self.mailbox.quota_bytes)
+                        .speed(64)
+                        .suffix(" bytes at most"),
+                );
+                let expiry = ui.add(
+                    egui::DragValue::new(&mut self.mailbox.expiry_hours).suffix(" hours at most"),
+                );
+                changed |= quota.lost_focus()
+                    || quota.drag_released()
+                    || expiry.lost_focus()
+                    || expiry.drag_released();
+                if let Some(node) = &self.node {
+                    let mut node = node.lock().unwrap();
+                    if changed {
+                        node.set_mailbox_policy(self.mailbox);
+                    }
+                    let mailbox = node.mailbox();
+                    ui.label(format!(
+                        "Holding {} messages ({} bytes)",
+                        mailbox.len(),
+                        mailbox.bytes()
+                    ));
+                }
+                ui.separator();
+                ui.label("Presence beacons (0 sends none)");
+                let mut changed = ui
+                    .add(
+                        egui::DragValue::new(&mut self.presence.interval_secs)
+                            .prefix("every ")
+                            .suffix(" s"),
+                    )
+                    .changed();
+                changed |= ui
+                    .add(
+                        egui::DragValue::new(&mut self.presence.ttl)
+                            .clamp_range(0..=protocol::MAX_TTL)
+                            .prefix("hops: "),
+                    )
+                    .changed();
+                ui.horizontal(|ui| {
+                    ui.label("Peers go idle after");
+                    changed |= ui
+                        .add(egui::DragValue::new(&mut self.presence.idle_after_secs).suffix(" s"))
+                        .changed();
+                    ui.label("and offline after");
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(&mut self.presence.offline_after_secs)
+                                .suffix(" s"),
+                        )
+                        .changed();
+                });
+                if changed {
+                    // The node reads its policy from the same table
+                    self.last_heard.lock().unwrap().set_policy(self.presence);
+                }
+                if let Some(node) = &self.node {
+                    ui.separator();
+                    let stats = node.lock().unwrap().dedupe_stats();
+                    ui.label(format!(
+                        "Repeated frames dropped: {} ({} forgotten early)",
+                        stats.suppressed, stats.evicted
+                    ));
+                }
+            });
+
+        self.show_settings = show_settings;
+
+        let mut show_address_book = self.show_address_book;
+        egui::Window::new("Address book")
+            .open(&mut show_address_book)
+            .show(ctx, |ui| {
+                ui.label("File");
+                ui.text_edit_singleline(&mut self.address_book_path);
+                ui.horizontal(|ui| {
+                    if ui.button("Import").clicked() {
+                        self.import_address_book();
+                    }
+                    if ui.button("Export").clicked() {
+                        self.export_address_book();
+                    }
+                });
+                if !self.address_book_status.is_empty() {
+                    ui.label(&self.address_book_status);
+                }
+            });
+        self.show_address_book = show_address_book;
+
+        let mut show_send_file = self.show_send_file;
+        egui::Window::new("Send file")
+            .open(&mut show_send_file)
+            .show(ctx, |ui| {
+                let to = self.target_user.as_deref().unwrap_or_default();
+                ui.label(format!("To {}", self.name(to)));
+                ui.label("File");
+                ui.text_edit_singleline(&mut self.file_path);
+                ui.weak(format!("At most {} KB", transfer::MAX_FILE_LEN / 1024));
+                let ready = self.target_user.is_some() && !self.file_path.trim().is_empty();
+                if ui.add_enabled(ready, egui::Button::new("Send")).clicked() {
+                    self.send_file();
+                }
+                if !self.file_status.is_empty() {
+                    ui.label(&self.file_status);
+                }
+            });
+        self.show_send_file = show_send_file;
+
+        if let Some(mut save) = self.save_file.take() {
+            let mut open = true;
+            egui::Window::new("Save file")
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    ui.label(format!(
+                        "{} ({}) from {}",
+                        save.file.name,
+                        file_size(save.file.size),
+                        self.name(&save.sender)
+                    ));
+                    ui.label("Save as");
+                    ui.text_edit_singleline(&mut save.path);
+                    if ui.button("Save").clicked() {
+                        save.status = self.save_received_file(&save);
+                    }
+                    if !save.status.is_empty() {
+                        ui.label(&save.status);
+                    }
+                });
+            if open {
+                self.save_file = Some(save);
+            }
+        }
+
+        if let Some(target_user) = self.target_user.clone() {
+            let mut show_contact = self.show_contact;
+            let mut remove = false;
+            egui::Window::new("Contact")
+                .open(&mut show_contact)
+                .show(ctx, |ui| {
+                    ui.label(&target_user);
+                    let contact = self.address_book.entry(&target_user);
+                    ui.label("Nickname");
+                    ui.text_edit_singleline(&mut contact.nickname);
+                    ui.label("Notes");
+                    ui.text_edit_multiline(&mut contact.notes);
+                    let mut keyring = self.keyring.lock().unwrap();
+                    if let Some(public) = keyring.peer(&target_user) {
+                        ui.label(format!("Key fingerprint: {}", public.fingerprint()));
+                        if ui
+                            .button("Forget key")
+                            .on_hover_text("Pin the next key it announces, e.g. after a reinstall")
+                            .clicked()
+                        {
+                            keyring.forget_peer(&target_user);
+                            // Or the old key would be exported and pinned elsewhere
+                            contact.public_key = None;
+                        }
+                    }
+                    drop(keyring);
+                    remove = ui.button("Remove contact").clicked();
+                });
+            if remove {
+                self.address_book.remove(&target_user);
+                show_contact = false;
+            }
+            self.show_contact = show_contact;
+        }
+
         egui::TopBottomPanel::bottom("bottom_panel").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.add_space(ui.available_size_before_wrap().x / 2.0 - 100.0); // Adjust the value as needed
-                ui.text_edit_singleline(&mut self.label);
-                if ui.button("Send").clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                    self.send_message(&self.label.clone());
+                let input = ui.text_edit_singleline(&mut self.label);
+                // Enter takes focus away from the field, so only count it if it did
+                let entered = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if (ui.button("Send").clicked() || entered) && self.send_message(&self.label) {
+                    self.label.clear();
+                }
+                if entered {
+                    input.request_focus();
                 }
+                if ui.button("📎").on_hover_text("Send a file").clicked() {
+                    self.show_send_file = true;
+                }
+                ui.add(
+                    egui::DragValue::new(&mut self.ttl)
+                        .clamp_range(0..=protocol::MAX_TTL)
+                        .prefix("hops: "),
+                )
+                .on_hover_text("How many relays may pass the message on");
                 ui.add_space(ui.available_size_before_wrap().x / 2.0 - 100.0); // Adjust the value as needed
             });
         });
 
+        if self.show_neighbors {
+            egui::SidePanel::right("neighbors").show(ctx, |ui| {
+                ui.heading("Neighbors");
+                ui.separator();
+                let neighbors = self.neighbors.lock().unwrap().clone();
+                if neighbors.is_empty() {
+                    ui.label("No station heard directly yet");
+                } else {
+                    egui::Grid::new("neighbor_grid")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.strong("Station");
+                            ui.strong("RSSI");
+                            ui.strong("SNR");
+                            ui.strong("Packets");
+                            ui.strong("Last heard");
+                            ui.end_row();
+                            for (uid, neighbor) in neighbors.iter() {
+                                let name = self.address_book.name(uid);
+                                if ui.link(name).on_hover_text(uid).clicked() {
+                                    self.target_user = Some(uid.clone());
+                                }
+                                ui.label(format!("{} dBm", neighbor.rssi));
+                                ui.label(format!("{} dB", neighbor.snr));
+                                ui.label(neighbor.packets.to_string());
+                                ui.label(clock_time(neighbor.last_seen));
+                                ui.end_row();
+                            }
+                        });
+                }
+
+                ui.add_space(8.0);
+                ui.heading("Routes");
+                ui.separator();
+                let mut routes = match &self.node {
+                    Some(node) => node.lock().unwrap().routes(),
+                    None => Vec::new(),
+                };
+                routes.retain(|(destination, route)| route.next_hop != *destination);
+                routes.sort_by(|a, b| a.0.cmp(&b.0));
+                if routes.is_empty() {
+                    ui.label("Messages beyond range are flooded until a route is found");
+                }
+                for (destination, route) in routes {
+                    ui.label(format!(
+                        "{} via {}",
+                        self.address_book.name(&destination),
+                        self.address_book.name(&route.next_hop),
+                    ))
+                    .on_hover_text(format!("{} relays on the way", route.hops));
+                }
+            });
+        }
+
+        egui::SidePanel::left("conversations").show(ctx, |ui| {
+            ui.heading("Conversations");
+            ui.separator();
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let presence = self.last_heard.lock().unwrap().clone();
+                for (peer, unread) in self.conversations() {
+                    let name = self.name(&peer);
+                    let text = if unread > 0 {
+                        format!("{} ({})", name, unread)
+                    } else {
+                        name.to_string()
+                    };
+                    let selected = self.target_user.as_ref() == Some(&peer);
+                    let channel = self.is_channel(&peer);
+                    ui.horizontal(|ui| {
+                        if channel {
+                            ui.weak("#");
+                        } else {
+                            presence_badge(
+                                ui,
+                                presence.status(&peer, node::now()),
+                                presence.last_heard(&peer),
+                            );
+                        }
+                        if ui.selectable_label(selected, text).clicked() {
+                            self.target_user = Some(peer.clone());
+                        }
+                    });
+                }
+            });
+            ui.separator();
+            ui.label("Add contact by UID");
+            ui.horizontal(|ui| {
+                let valid = protocol::is_uid(self.new_contact.trim());
+                ui.text_edit_singleline(&mut self.new_contact);
+                if ui.add_enabled(valid, egui::Button::new("Add")).clicked() {
+                    self.add_contact();
+                }
+            });
+            ui.label("Join channel by name");
+            ui.text_edit_singleline(&mut self.new_channel.name);
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.new_channel.secret)
+                        .password(true)
+                        .hint_text("group passphrase"),
+                )
+                .on_hover_text("Leave empty to use the mesh passphrase");
+                let valid = !self.new_channel.name.trim().is_empty();
+                if ui.add_enabled(valid, egui::Button::new("Join")).clicked() {
+                    self.join_channel();
+                }
+            });
+        });
+
+        let mut retry = None;
+        let mut resume = Vec::new();
+        let mut save = None;
+        let mut leave = None;
         egui::CentralPanel::default().show(ctx, |ui| {
+            let mut dismissed = None;
+            for (index, notification) in self.notifications.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.colored_label(ui.visuals().warn_fg_color, notification);
+                    if ui.small_button("Dismiss").clicked() {
+                        dismissed = Some(index);
+                    }
+                });
+            }
+            if let Some(index) = dismissed {
+                self.notifications.remove(index);
+            }
+            if !self.notifications.is_empty() {
+                ui.separator();
+            }
+            if let Some(target_user) = self.target_user.as_ref().filter(|uid| self.is_channel(uid))
+            {
+                ui.horizontal(|ui| {
+                    ui.strong(self.name(target_user));
+                    if target_user == protocol::BROADCAST {
+                        ui.weak("every station in the mesh");
+                    } else {
+                        ui.weak("channel");
+                        if ui.button("Leave channel").clicked() {
+                            leave = Some(target_user.clone());
+                        }
+                    }
+                });
+                ui.separator();
+            } else if let Some(target_user) = self.target_user.as_ref() {
+                let verification = match self.keyring.lock().unwrap().peer(target_user) {
+                    Some(public) => format!("Verified key {}", public.fingerprint()),
+                    None => "Not verified".to_string(),
+                };
+                let (status, last_heard) = {
+                    let presence = self.last_heard.lock().unwrap();
+                    (
+                        presence.status(target_user, node::now()),
+                        presence.last_heard(target_user),
+                    )
+                };
+                ui.horizontal(|ui| {
+                    presence_badge(ui, status, last_heard);
+                    ui.strong(self.address_book.name(target_user));
+                    if self.address_book.name(target_user) != target_user {
+                        ui.weak(target_user);
+                    }
+                    ui.label(verification);
+                    if ui.button("Edit contact").clicked() {
+                        self.show_contact = true;
+                    }
+                });
+                ui.separator();
+            }
+            let channel = self
+                .target_user
+                .as_deref()
+                .is_some_and(|uid| self.is_channel(uid));
+            // Before the messages are locked, as the node locks them itself
+            let incoming = match &self.node {
+                Some(node) => node.lock().unwrap().incoming(),
+                None => Vec::new(),
+            };
+            for progress in incoming {
+                let conversation = if self.is_channel(&progress.recipient) {
+                    &progress.recipient
+                } else {
+                    &progress.sender
+                };
+                if self.target_user.as_ref() != Some(conversation) {
+                    continue;
+                }
+                ui.horizontal(|ui| {
+                    ui.weak(format!("{} is sending", self.name(&progress.sender)));
+                    ui.add(
+                        egui::ProgressBar::new(progress.received as f32 / progress.count as f32)
+                            .text(format!("{}/{} parts", progress.received, progress.count))
+                            .desired_width(160.0),
+                    );
+                });
+            }
             // Check for new data from the serial port
             ui.vertical_centered(|ui| {
                 egui::ScrollArea::vertical().show(ui, |ui| {
                     // Example long content to demonstrate scrolling
                     match self.shared_messages.lock() {
                         Ok(mut messages) => {
-                            let target_user = self.target_user.lock().unwrap();
-                            let target_user_ref = target_user.as_ref().unwrap();
-                            let target_vec = messages.get_mut(target_user_ref); // get mutable reference
+                            let Some(target_user) = self.target_user.as_ref() else {
+                                ui.label("Pick a conversation or add a contact");
+                                return;
+                            };
+                            let target_vec = messages.get_mut(target_user); // get mutable reference
                             match target_vec {
                                 Some(target_messages) => {
+                                    // Everything on screen counts as read
+                                    if let Some(latest) =
+                                        target_messages.iter().map(|message| message.time).max()
+                                    {
+                                        self.last_read.insert(target_user.clone(), latest);
+                                    }
+                                    let own = self.userid.clone().unwrap();
+                                    // A file shows once, where its first chunk is
+                                    let transfers = transfer::transfers(target_messages, &own);
+                                    let mut shown = HashSet::new();
                                     for i in target_messages.iter_mut() {
+                                        if let Some((file, _)) = transfer::header(&i.data) {
+                                            let transfer = transfers.iter().find(|transfer| {
+                                                transfer.sender == i.sender && transfer.file == file
+                                            });
+                                            if let Some(transfer) = transfer {
+                                                if shown.insert((&transfer.sender, &transfer.file))
+                                                {
+                                                    let action = file_row(
+                                                        ui,
+                                                        transfer,
+                                                        self.name(&transfer.sender),
+                                                        transfer.sender == own,
+                                                        channel,
+                                                    );
+                                                    match action {
+                                                        Some(FileAction::Resume) => {
+                                                            resume = transfer.failed.clone()
+                                                        }
+                                                        Some(FileAction::Save) => {
+                                                            save = Some(transfer.clone())
+                                                        }
+                                                        None => {}
+                                                    }
+                                                }
+                                            }
+                                            continue;
+                                        }
                                         // iterate over mutable references
-                                        if i.sender != self.userid.clone().unwrap() {
+                                        if i.sender != own {
                                             ui.horizontal(|ui| {
-                                                ui.label(format!("{}", i.data));
+                                                let name = self.address_book.name(&i.sender);
+                                                ui.label(format!("{}: {}", name, i.data));
+                                                ui.weak(clock_time(i.time));
+                                                if i.hops > 0 {
+                                                    let plural = if i.hops == 1 { "" } else { "s" };
+                                                    ui.weak(format!(
+                                                        "via {} relay{}",
+                                                        i.hops, plural
+                                                    ));
+                                                }
                                                 // This spacer pushes everything to the left, showing the scroll area's full width
                                                 ui.add_space(ui.available_width());
                                             });
@@ -170,49 +928,16 @@ impl eframe::App for TemplateApp {
                                                 ui.with_layout(
                                                     egui::Layout::right_to_left(egui::Align::Max),
                                                     |ui| {
-                                                        ui.label(format!("{}", i.data));
+                                                        if i.state == DeliveryState::Failed
+                                                            && ui.small_button("Retry").clicked()
+                                                        {
+                                                            retry = i.id;
+                                                        }
+                                                        status(ui, i, channel);
+                                                        ui.label(&i.data);
                                                     },
                                                 );
                                             });
-                                            if !i.confirmed
-                                                && i.count < 4
-                                                && i.time + (10 * i.count)
-                                                    < SystemTime::now()
-                                                        .duration_since(SystemTime::UNIX_EPOCH)
-                                                        .unwrap()
-                                                        .as_secs()
-                                            {
-                                                let command = format!(
-                                                    "AT+SEND=0,{},{}{}{}{}\r\n",
-                                                    (i.recipient.len()
-                                                        + i.sender.len()
-                                                        + i.time.to_string().len()
-                                                        + i.data.len()),
-                                                    i.recipient,
-                                                    i.sender,
-                                                    i.time,
-                                                    i.data
-                                                );
-                                                match self.port.lock() {
-                                                    Ok(mut port_option) => {
-                                                        if let Some(port) = port_option.as_mut() {
-                                                            match port.write(command.as_bytes()) {
-                                                                Ok(_) => {
-                                                                    i.count += 1;
-                                                                }
-                                                                Err(_) => {
-                                                                    eprintln!(
-                                                                        "Error writing to port"
-                                                                    );
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                    Err(_) => {
-                                                        println!("Error locking the port");
-                                                    }
-                                                }
-                                            }
                                         }
                                     }
                                 }
@@ -229,6 +954,167 @@ impl eframe::App for TemplateApp {
             });
         });
 
+        // The node locks the messages itself, so only once they are released here
+        if let (Some(id), Some(peer)) = (retry, &self.target_user) {
+            self.retry(peer, id);
+        }
+        if let Some(peer) = self.target_user.clone() {
+            for id in resume {
+                self.retry(&peer, id);
+            }
+            if let Some(transfer) = save {
+                let path = Path::new(self.file_path.trim()).with_file_name(&transfer.file.name);
+                self.save_file = Some(SaveFile {
+                    peer,
+                    sender: transfer.sender,
+                    file: transfer.file,
+                    path: path.display().to_string(),
+                    status: String::new(),
+                });
+            }
+        }
+        if let Some(channel) = leave {
+            self.leave_channel(&channel);
+        }
+
         ctx.request_repaint()
     }
 }
+
+/// A received file the save dialog is open for.
+struct SaveFile {
+    /// The conversation it arrived in
+    peer: String,
+    sender: String,
+    file: FileInfo,
+    path: String,
+    status: String,
+}
+
+/// What the user asked for in a [`file_row`].
+enum FileAction {
+    Resume,
+    Save,
+}
+
+/// A file sent in the conversation, with how far it got and what can be done with it.
+fn file_row(
+    ui: &mut egui::Ui,
+    transfer: &Transfer,
+    sender: &str,
+    ours: bool,
+    channel: bool,
+) -> Option<FileAction> {
+    let mut action = None;
+    let file = format!(
+        "📎 {} ({})",
+        transfer.file.name,
+        file_size(transfer.file.size)
+    );
+    let progress = |ui: &mut egui::Ui| {
+        let Transfer { done, file, .. } = transfer;
+        ui.add(
+            egui::ProgressBar::new(*done as f32 / file.count as f32)
+                .text(format!("{}/{} parts", done, file.count))
+                .desired_width(160.0),
+        );
+    };
+    ui.horizontal(|ui| {
+        if ours {
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Max), |ui| {
+                if !transfer.failed.is_empty() && ui.small_button("Resume").clicked() {
+                    action = Some(FileAction::Resume);
+                }
+                let time = clock_time(transfer.time);
+                if !transfer.complete() {
+                    ui.weak(time);
+                    progress(ui);
+                } else if channel {
+                    ui.weak(format!("{} ✔ sent", time));
+                } else {
+                    ui.weak(format!("{} ✔ delivered", time));
+                }
+                ui.label(file);
+            });
+        } else {
+            ui.label(format!("{}: {}", sender, file));
+            if transfer.complete() {
+                if ui.small_button("Save…").clicked() {
+                    action = Some(FileAction::Save);
+                }
+            } else {
+                progress(ui);
+            }
+            ui.weak(clock_time(transfer.time));
+            ui.add_space(ui.available_width());
+        }
+    });
+    action
+}
+
+/// `bytes` for people, in B or KB.
+fn file_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    }
+}
+
+/// The send time and delivery status shown beside one of our messages.
+fn status(ui: &mut egui::Ui, message: &Message, channel: bool) {
+    let time = clock_time(message.time);
+    match message.state {
+        // Channel messages aren't confirmed, only sent
+        DeliveryState::Delivered if channel => {
+            ui.weak(format!("{} ✔ sent", time));
+        }
+        DeliveryState::Queued | DeliveryState::Sent | DeliveryState::Retrying
+            if message.parts > 0 =>
+        {
+            ui.weak(format!(
+                "{} pending, {}/{} parts",
+                time, message.parts_acked, message.parts
+            ));
+        }
+        DeliveryState::Queued | DeliveryState::Sent => {
+            ui.weak(format!("{} pending", time));
+        }
+        DeliveryState::Retrying => {
+            ui.weak(format!("{} pending, try {}", time, message.count));
+        }
+        DeliveryState::Delivered => {
+            ui.weak(format!("{} ✔ delivered", time));
+        }
+        DeliveryState::Failed => {
+            ui.colored_label(ui.visuals().error_fg_color, format!("{} ✖ failed", time));
+        }
+    }
+}
+
+/// A dot coloured by whether a peer is online, saying when it was last heard on hover.
+fn presence_badge(ui: &mut egui::Ui, presence: Presence, last_heard: Option<u64>) {
+    let color = match presence {
+        Presence::Online => egui::Color32::from_rgb(0x3C, 0xB3, 0x71),
+        Presence::Idle => ui.visuals().warn_fg_color,
+        Presence::Offline => ui.visuals().weak_text_color(),
+    };
+    let hover = match last_heard {
+        Some(time) => format!("{}, last heard {}", presence, clock_time(time)),
+        None => format!("{}, never heard", presence),
+    };
+    ui.colored_label(color, "●").on_hover_text(hover);
+}
+
+/// `time` (UNIX seconds) as local `HH:MM`, with the date if it isn't today.
+fn clock_time(time: u64) -> String {
+    let Some(time) = chrono::DateTime::from_timestamp(time as i64, 0) else {
+        return String::new();
+    };
+    let time = time.with_timezone(&chrono::Local);
+    if time.date_naive() == chrono::Local::now().date_naive() {
+        time.format("%H:%M").to_string()
+    } else {
+        time.format("%Y-%m-%d %H:%M").to_string()
+    }
+}
@@ -0,0 +1,29 @@
+use crate::protocol::UID_LEN;
+use sha2::{Digest, Sha256};
+
+/// A named group conversation. Every station that joined under the same name gets its
+/// messages, which are addressed to [`channel_uid`] of the name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Channel {
+    pub name: String,
+    /// Passphrase the group key is derived from; empty falls back to the mesh passphrase
+    pub secret: String,
+}
+
+impl Channel {
+    pub fn uid(&self) -> String {
+        channel_uid(&self.name)
+    }
+}
+
+/// The address of the channel called `name`: 24 hex digits, like a module UID.
+///
+/// Relays can't tell it from a station they haven't met, so they flood it like one.
+pub fn channel_uid(name: &str) -> String {
+    let digest = Sha256::digest(format!("lora_mesh channel\0{}", name).as_bytes());
+    digest[..UID_LEN / 2]
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
+}
@@ -0,0 +1,101 @@
+use crate::crypto::{Keyring, Pin};
+use crate::identity::PublicIdentity;
+use crate::storage;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What we know about one peer beyond its UID.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Contact {
+    pub nickname: String,
+    pub notes: String,
+    /// The peer's [`PublicIdentity`], encoded, once we have seen or imported it
+    pub public_key: Option<String>,
+}
+
+/// Contacts by UID. Persisted with the rest of the app state, and exchanged between
+/// stations as a RON file.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct AddressBook {
+    contacts: BTreeMap<String, Contact>,
+}
+
+impl AddressBook {
+    pub fn get(&self, uid: &str) -> Option<&Contact> {
+        self.contacts.get(uid)
+    }
+
+    /// The entry for `uid`, created empty if it is new.
+    pub fn entry(&mut self, uid: &str) -> &mut Contact {
+        self.contacts.entry(uid.to_string()).or_default()
+    }
+
+    pub fn remove(&mut self, uid: &str) {
+        self.contacts.remove(uid);
+    }
+
+    pub fn uids(&self) -> impl Iterator<Item = &String> {
+        self.contacts.keys()
+    }
+
+    /// The nickname for `uid`, or the UID itself if it has none.
+    pub fn name<'a>(&'a self, uid: &'a str) -> &'a str {
+        match self.contacts.get(uid) {
+            Some(contact) if !contact.nickname.is_empty() => &contact.nickname,
+            _ => uid,
+        }
+    }
+
+    /// Copy keys the keyring has pinned into the matching entries, replacing any other
+    /// key an entry holds, e.g. one forgotten since.
+    pub fn record_keys(&mut self, keyring: &Keyring) {
+        for (uid, contact) in &mut self.contacts {
+            if let Some(public) = keyring.peer(uid) {
+                contact.public_key = Some(public.encode());
+            }
+        }
+    }
+
+    pub fn export(&self, path: &Path) -> io::Result<()> {
+        storage::save(path, self)
+    }
+
+    /// Merge the address book in `path` into this one, pinning any keys it carries.
+    ///
+    /// Imported fields only fill in what is empty here, so local edits win. Returns how
+    /// many entries the file held.
+    pub fn import(&mut self, path: &Path, keyring: &mut Keyring) -> io::Result<usize> {
+        let text = fs::read_to_string(path)?;
+        let imported: AddressBook =
+            ron::from_str(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        for (uid, contact) in &imported.contacts {
+            if let Some(public) = contact
+                .public_key
+                .as_deref()
+                .and_then(PublicIdentity::decode)
+            {
+                if keyring.pin(uid, public) == Pin::Conflict {
+                    eprintln!(
+                        "Not importing the key for {}: differs from the pinned one",
+                        uid
+                    );
+                }
+            }
+            let pinned = keyring.peer(uid).map(PublicIdentity::encode);
+            let entry = self.entry(uid);
+            if entry.nickname.is_empty() {
+                entry.nickname = contact.nickname.clone();
+            }
+            if entry.notes.is_empty() {
+                entry.notes = contact.notes.clone();
+            }
+            // Whatever the keyring trusts, which is the import unless it conflicted
+            entry.public_key = pinned;
+        }
+        Ok(imported.contacts.len())
+    }
+}
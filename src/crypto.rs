@@ -0,0 +1,352 @@
+use crate::identity::{Identity, PublicIdentity};
+use crate::protocol;
+use crate::storage;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Marks a message body as sealed: `!` followed by base64 of `nonce || ciphertext`.
+pub const SEALED_PREFIX: char = '!';
+
+const NONCE_LEN: usize = 12;
+/// Bytes of HMAC-SHA256 kept in a confirmation tag.
+const TAG_BYTES: usize = 16;
+
+/// The secrets this station shares with its peers.
+///
+/// The preferred key for a peer comes from an X25519 exchange with the public keys it
+/// announced. Without those, a key comes from that peer's own passphrase if one is set,
+/// or else from the mesh-wide passphrase. Both ends derive the same key because the
+/// UIDs are mixed in in sorted order.
+///
+/// The channels we joined are kept here too, as everyone in one shares a single key: from
+/// the channel's own passphrase, or else the mesh-wide one. The broadcast address is a
+/// channel everyone is in.
+pub struct Keyring {
+    identity: Identity,
+    /// Public keys pinned on first sight, by UID
+    peers: HashMap<String, PublicIdentity>,
+    peers_path: Option<PathBuf>,
+    mesh_secret: Option<String>,
+    peer_secrets: HashMap<String, String>,
+    /// Passphrases by channel UID, empty where the mesh passphrase applies
+    channels: HashMap<String, String>,
+}
+
+/// The keyring shared between the node and the UI.
+pub type SharedKeyring = Arc<Mutex<Keyring>>;
+
+/// What [`Keyring::pin`] made of an announced key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pin {
+    /// First key seen for this UID, now pinned.
+    New,
+    /// Same key as already pinned.
+    Known,
+    /// A different key than the pinned one; ignored.
+    Conflict,
+}
+
+impl Default for Keyring {
+    fn default() -> Self {
+        Self {
+            identity: Identity::generate(),
+            peers: HashMap::new(),
+            peers_path: None,
+            mesh_secret: None,
+            peer_secrets: HashMap::new(),
+            channels: HashMap::new(),
+        }
+    }
+}
+
+impl Keyring {
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    pub fn set_identity(&mut self, identity: Identity) {
+        self.identity = identity;
+    }
+
+    /// Load pinned peer keys from `path` and save them there whenever one is added.
+    pub fn persist_peers(&mut self, path: PathBuf) {
+        let stored: HashMap<String, String> = storage::load(&path).unwrap_or_default();
+        for (uid, encoded) in stored {
+            if let Some(public) = PublicIdentity::decode(&encoded) {
+                self.peers.insert(uid, public);
+            }
+        }
+        self.peers_path = Some(path);
+    }
+
+    fn save_peers(&self) {
+        if let Some(path) = &self.peers_path {
+            let stored: HashMap<&String, String> = self
+                .peers
+                .iter()
+                .map(|(uid, public)| (uid, public.encode()))
+                .collect();
+            if let Err(err) = storage::save(path, &stored) {
+                eprintln!("Failed to save peer keys: {}", err);
+            }
+        }
+    }
+
+    /// Trust `public` for `uid` unless a different key is already pinned.
+    pub fn pin(&mut self, uid: &str, public: PublicIdentity) -> Pin {
+        match self.peers.get(uid) {
+            Some(pinned) if *pinned == public => Pin::Known,
+            Some(_) => Pin::Conflict,
+            None => {
+                self.peers.insert(uid.to_string(), public);
+                self.save_peers();
+                Pin::New
+            }
+        }
+    }
+
+    /// Drop the pinned key for `uid`, e.g. after the peer reinstalled.
+    pub fn forget_peer(&mut self, uid: &str) {
+        if self.peers.remove(uid).is_some() {
+            self.save_peers();
+        }
+    }
+
+    pub fn peer(&self, uid: &str) -> Option<&PublicIdentity> {
+        self.peers.get(uid)
+    }
+
+    /// Whether we hold a signed binding of public keys to `uid`.
+    pub fn is_verified(&self, uid: &str) -> bool {
+        self.peers.contains_key(uid)
+    }
+
+    /// Set or clear (with an empty string) the passphrase used with every peer.
+    pub fn set_mesh_secret(&mut self, secret: &str) {
+        self.mesh_secret = (!secret.is_empty()).then(|| secret.to_string());
+    }
+
+    /// Set or clear (with an empty string) the passphrase used with one peer.
+    pub fn set_peer_secret(&mut self, peer: &str, secret: &str) {
+        if secret.is_empty() {
+            self.peer_secrets.remove(peer);
+        } else {
+            self.peer_secrets
+                .insert(peer.to_string(), secret.to_string());
+        }
+    }
+
+    /// Take messages to `channel`, sealed with a key from `secret` (or the mesh
+    /// passphrase, if empty).
+    pub fn join_channel(&mut self, channel: &str, secret: &str) {
+        self.channels
+            .insert(channel.to_string(), secret.to_string());
+    }
+
+    pub fn leave_channel(&mut self, channel: &str) {
+        self.channels.remove(channel);
+    }
+
+    /// Whether `uid` is the broadcast address or a channel we joined.
+    pub fn is_channel(&self, uid: &str) -> bool {
+        uid == protocol::BROADCAST || self.channels.contains_key(uid)
+    }
+
+    /// The key shared by everyone in `channel`, if encryption is on for it.
+    fn channel_key(&self, channel: &str) -> Option<Key> {
+        let secret = match self.channels.get(channel) {
+            Some(secret) if !secret.is_empty() => secret,
+            _ => self.mesh_secret.as_ref()?,
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(b"lora_mesh channel key\0");
+        hasher.update(secret.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(channel.as_bytes());
+        Some(hasher.finalize())
+    }
+
+    /// The key to seal traffic from `own` to `peer` with, if encryption is on for them.
+    pub fn pair_key(&self, own: &str, peer: &str) -> Option<Key> {
+        self.candidate_keys(own, peer).into_iter().next()
+    }
+
+    /// Every key `peer` might have sealed a frame with, best first.
+    ///
+    /// Once a peer's key is pinned only the key from the exchange is accepted, as anyone
+    /// with the passphrase could otherwise speak for it. Until then a passphrase key is.
+    pub fn candidate_keys(&self, own: &str, peer: &str) -> Vec<Key> {
+        // Whoever sent to a channel, it is sealed under the channel's key
+        if let Some(channel) = [own, peer].into_iter().find(|uid| self.is_channel(uid)) {
+            return self.channel_key(channel).into_iter().collect();
+        }
+        if let Some(public) = self.peers.get(peer) {
+            return vec![self.identity.shared_key(own, peer, public)];
+        }
+        let secrets = [self.peer_secrets.get(peer), self.mesh_secret.as_ref()];
+        secrets
+            .into_iter()
+            .flatten()
+            .map(|secret| passphrase_key(secret, own, peer))
+            .collect()
+    }
+}
+
+fn passphrase_key(secret: &str, own: &str, peer: &str) -> Key {
+    let (low, high) = if own <= peer {
+        (own, peer)
+    } else {
+        (peer, own)
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(b"lora_mesh pair key\0");
+    hasher.update(secret.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(low.as_bytes());
+    hasher.update(high.as_bytes());
+    hasher.finalize()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CryptoError {
+    /// The body was sent in the clear although we share a key with the sender.
+    NotSealed,
+    /// The body isn't valid base64 or is too short to hold a nonce.
+    Malformed,
+    /// Decryption failed: wrong key, or the frame was altered on the way.
+    Authentication,
+    /// The body is sealed but we share no key with the sender.
+    NoKey,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::NotSealed => write!(f, "body is not encrypted"),
+            CryptoError::Malformed => write!(f, "malformed sealed body"),
+            CryptoError::Authentication => write!(f, "authentication failed"),
+            CryptoError::NoKey => write!(f, "body is encrypted but no key is set"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// Encrypt `text` for the wire. `header` (the routing fields) is authenticated but left
+/// readable so relays can still forward the frame.
+pub fn seal(key: &Key, header: &str, text: &str) -> String {
+    seal_with_nonce(
+        key,
+        ChaCha20Poly1305::generate_nonce(&mut OsRng),
+        header,
+        text,
+    )
+}
+
+/// Like [`seal`], but with the nonce derived from the key, header and text, so the same
+/// message always seals to the same body. Fragments of it resent after a restart or a
+/// manual retry then still fit the ones that arrived before.
+///
+/// The header carries the message ID, so two different messages never share a nonce.
+pub fn seal_repeatably(key: &Key, header: &str, text: &str) -> String {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(b"lora_mesh nonce\0");
+    mac.update(header.as_bytes());
+    mac.update(b"\0");
+    mac.update(text.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    seal_with_nonce(key, *Nonce::from_slice(&digest[..NONCE_LEN]), header, text)
+}
+
+fn seal_with_nonce(key: &Key, nonce: Nonce, header: &str, text: &str) -> String {
+    let cipher = ChaCha20Poly1305::new(key);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: text.as_bytes(),
+                aad: header.as_bytes(),
+            },
+        )
+        .expect("encrypting into a Vec can't fail");
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    format!("{}{}", SEALED_PREFIX, STANDARD_NO_PAD.encode(sealed))
+}
+
+/// Whether `body` looks like [`seal`] made it, rather than text someone typed with a
+/// leading `!`.
+pub fn is_sealed(body: &str) -> bool {
+    body.strip_prefix(SEALED_PREFIX)
+        .and_then(|encoded| STANDARD_NO_PAD.decode(encoded).ok())
+        .is_some_and(|sealed| sealed.len() >= NONCE_LEN + TAG_BYTES)
+}
+
+/// Reverse [`seal`], rejecting bodies that were not sealed with `key` and `header`.
+pub fn open(key: &Key, header: &str, body: &str) -> Result<String, CryptoError> {
+    let encoded = body
+        .strip_prefix(SEALED_PREFIX)
+        .ok_or(CryptoError::NotSealed)?;
+    let sealed = STANDARD_NO_PAD
+        .decode(encoded)
+        .map_err(|_| CryptoError::Malformed)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::Malformed);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let text = ChaCha20Poly1305::new(key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header.as_bytes(),
+            },
+        )
+        .map_err(|_| CryptoError::Authentication)?;
+    String::from_utf8(text).map_err(|_| CryptoError::Malformed)
+}
+
+/// Try [`open`] with each key in turn, returning the text and the key that fit, or the
+/// first key's error if none does.
+pub fn open_with_any(keys: &[Key], header: &str, body: &str) -> Result<(String, Key), CryptoError> {
+    let mut first_error = None;
+    for key in keys {
+        match open(key, header, body) {
+            Ok(text) => return Ok((text, *key)),
+            Err(err) => {
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    Err(first_error.unwrap_or(CryptoError::Authentication))
+}
+
+/// A truncated HMAC-SHA256 of `data`, base64 encoded.
+pub fn tag(key: &Key, data: &str) -> String {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    STANDARD_NO_PAD.encode(&mac.finalize().into_bytes()[..TAG_BYTES])
+}
+
+/// Whether `tag` was made by [`tag`] over `data` with one of `keys`.
+pub fn verify_tag(keys: &[Key], data: &str, tag: &str) -> bool {
+    let Ok(tag) = STANDARD_NO_PAD.decode(tag) else {
+        return false;
+    };
+    keys.iter().any(|key| {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(data.as_bytes());
+        tag.len() == TAG_BYTES && mac.verify_truncated_left(&tag).is_ok()
+    })
+}
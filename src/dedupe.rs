@@ -0,0 +1,139 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How much the duplicate cache holds and for how long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupePolicy {
+    /// Frames remembered at most; the oldest is forgotten first.
+    pub capacity: usize,
+    /// How long a frame is remembered.
+    pub lifetime: Duration,
+}
+
+impl Default for DedupePolicy {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            lifetime: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Counts kept by a [`DedupeCache`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupeStats {
+    /// Frames dropped because they were seen recently
+    pub suppressed: u64,
+    /// Frames forgotten before their lifetime was up, to stay within capacity
+    pub evicted: u64,
+}
+
+/// Recently seen frames, so copies arriving over other paths aren't handled twice.
+///
+/// Entries expire in the order they were added, so a queue beside the map is enough
+/// to drop them without scanning.
+#[derive(Debug, Default)]
+pub struct DedupeCache {
+    seen: HashMap<String, Instant>,
+    order: VecDeque<String>,
+    policy: DedupePolicy,
+    stats: DedupeStats,
+}
+
+impl DedupeCache {
+    pub fn policy(&self) -> DedupePolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: DedupePolicy) {
+        self.policy = policy;
+        self.trim(Instant::now());
+    }
+
+    pub fn stats(&self) -> DedupeStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Whether `key` is new at `now`, counting a suppressed duplicate if not, without
+    /// remembering it. For frames that are only [`insert`](Self::insert)ed once they
+    /// proved genuine.
+    pub fn check(&mut self, key: &str, now: Instant) -> bool {
+        self.trim(now);
+        if self.seen.contains_key(key) {
+            self.stats.suppressed += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Remember `key` as seen at `now`. Returns false, and counts a suppressed
+    /// duplicate, if it was seen within the lifetime already.
+    pub fn insert(&mut self, key: String, now: Instant) -> bool {
+        self.trim(now);
+        if self.seen.contains_key(&key) {
+            self.stats.suppressed += 1;
+            return false;
+        }
+        self.seen.insert(key.clone(), now);
+        self.order.push_back(key);
+        self.trim(now);
+        true
+    }
+
+    /// Drop expired entries, then the oldest ones beyond capacity.
+    fn trim(&mut self, now: Instant) {
+        while let Some(oldest) = self.order.front() {
+            let added = self.seen[oldest];
+            let expired = now.saturating_duration_since(added) > self.policy.lifetime;
+            let over = self.order.len() > self.policy.capacity;
+            if !expired && !over {
+                break;
+            }
+            if !expired {
+                self.stats.evicted += 1;
+            }
+            let oldest = self.order.pop_front().unwrap();
+            self.seen.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedupe_cache_expires_and_evicts() {
+        let mut cache = DedupeCache::default();
+        cache.set_policy(DedupePolicy {
+            capacity: 2,
+            lifetime: Duration::from_secs(5),
+        });
+        let start = Instant::now();
+        assert!(cache.insert("a".to_string(), start));
+        assert!(!cache.insert("a".to_string(), start + Duration::from_secs(1)));
+        assert!(cache.insert("a".to_string(), start + Duration::from_secs(6)));
+
+        let later = start + Duration::from_secs(7);
+        assert!(cache.insert("b".to_string(), later));
+        assert!(cache.insert("c".to_string(), later));
+        assert_eq!(cache.len(), 2);
+        // "a" was pushed out to make room, so it is new again
+        assert!(cache.insert("a".to_string(), later));
+        assert_eq!(
+            cache.stats(),
+            DedupeStats {
+                suppressed: 1,
+                evicted: 2,
+            }
+        );
+    }
+}
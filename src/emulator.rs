@@ -0,0 +1,216 @@
+use crate::protocol::{MAX_PAYLOAD, UID_LEN};
+use crate::transport::Transport;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Loopback ports emulated modules transmit to; each instance binds the first free one.
+const AIR_PORTS: std::ops::Range<u16> = 47100..47108;
+
+/// Signal reported for packets heard over the loopback "air".
+const LOOPBACK_RSSI: i32 = -40;
+const LOOPBACK_SNR: i32 = 11;
+
+/// The command set of an RYLR module, independent of how packets travel.
+pub struct AtModule {
+    uid: String,
+    address: u16,
+    network_id: u8,
+    parameter: String,
+}
+
+/// The module's answer to one command line.
+pub struct Response {
+    /// The `+OK` / `+ERR=` / query line the module prints.
+    pub reply: String,
+    /// Data to put on the air, for `AT+SEND`.
+    pub transmit: Option<String>,
+}
+
+impl AtModule {
+    pub fn new(uid: String) -> Self {
+        Self {
+            uid,
+            address: 0,
+            network_id: 18,
+            parameter: "9,7,1,12".to_string(),
+        }
+    }
+
+    pub fn uid(&self) -> &str {
+        &self.uid
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// Handle one command line (without `\r\n`).
+    pub fn command(&mut self, line: &str) -> Response {
+        let reply = |reply: &str| Response {
+            reply: reply.to_string(),
+            transmit: None,
+        };
+        // Only the line ending goes: spaces at the end of a payload are data
+        let line = line.trim_end_matches(['\r', '\n']);
+        let Some(command) = line.strip_prefix("AT") else {
+            return reply("+ERR=2");
+        };
+        match command.split_once('=') {
+            None => match command {
+                "" => reply("+OK"),
+                "+UID?" => reply(&format!("+UID={}", self.uid)),
+                "+ADDRESS?" => reply(&format!("+ADDRESS={}", self.address)),
+                "+NETWORKID?" => reply(&format!("+NETWORKID={}", self.network_id)),
+                "+PARAMETER?" => reply(&format!("+PARAMETER={}", self.parameter)),
+                _ => reply("+ERR=4"),
+            },
+            Some(("+SEND", args)) => {
+                let mut parts = args.splitn(3, ',');
+                let _address = parts.next();
+                let length = parts.next().and_then(|len| len.parse::<usize>().ok());
+                let data = parts.next().unwrap_or_default();
+                if length != Some(data.len()) || data.len() > MAX_PAYLOAD {
+                    reply("+ERR=5")
+                } else {
+                    Response {
+                        reply: "+OK".to_string(),
+                        transmit: Some(data.to_string()),
+                    }
+                }
+            }
+            Some(("+ADDRESS", value)) => match value.parse() {
+                Ok(address) => {
+                    self.address = address;
+                    reply("+OK")
+                }
+                Err(_) => reply("+ERR=4"),
+            },
+            Some(("+NETWORKID", value)) => match value.parse() {
+                Ok(network_id) => {
+                    self.network_id = network_id;
+                    reply("+OK")
+                }
+                Err(_) => reply("+ERR=4"),
+            },
+            Some(("+PARAMETER", value)) => {
+                if value.split(',').count() == 4
+                    && value.split(',').all(|p| p.parse::<u8>().is_ok())
+                {
+                    self.parameter = value.to_string();
+                    reply("+OK")
+                } else {
+                    reply("+ERR=4")
+                }
+            }
+            Some(_) => reply("+ERR=4"),
+        }
+    }
+
+    /// The line the module prints when it hears `data` from the module at `address`.
+    pub fn receive(&self, address: u16, data: &str, rssi: i32, snr: i32) -> String {
+        format!("+RCV={},{},{},{},{}", address, data.len(), data, rssi, snr)
+    }
+}
+
+/// A software stand-in for the radio that lets several app instances on one machine
+/// reach each other over loopback UDP.
+pub struct EmulatedRadio {
+    module: AtModule,
+    socket: UdpSocket,
+    lines: VecDeque<String>,
+}
+
+impl EmulatedRadio {
+    /// Join the loopback air with the given UID, or a random one.
+    pub fn open(uid: Option<String>) -> io::Result<Self> {
+        let socket = AIR_PORTS
+            .clone()
+            .find_map(|port| UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).ok())
+            .ok_or(io::ErrorKind::AddrInUse)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            module: AtModule::new(uid.unwrap_or_else(random_uid)),
+            socket,
+            lines: VecDeque::new(),
+        })
+    }
+
+    fn transmit(&self, data: &str) -> io::Result<()> {
+        let own_port = self.socket.local_addr()?.port();
+        let packet = format!("{},{}", self.module.address(), data);
+        for port in AIR_PORTS.filter(|port| *port != own_port) {
+            // Nobody listening on a port is the normal case
+            let _ = self
+                .socket
+                .send_to(packet.as_bytes(), (Ipv4Addr::LOCALHOST, port));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buf = [0; 512];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((count, _)) => {
+                    let packet = String::from_utf8_lossy(&buf[..count]);
+                    if let Some((address, data)) = packet.split_once(',') {
+                        let address = address.parse().unwrap_or_default();
+                        let line = self
+                            .module
+                            .receive(address, data, LOOPBACK_RSSI, LOOPBACK_SNR);
+                        self.lines.push_back(line);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                // Windows reports ICMP port unreachable from earlier sends here
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Transport for EmulatedRadio {
+    fn send_line(&mut self, line: &str) -> io::Result<()> {
+        let response = self.module.command(line);
+        if let Some(data) = &response.transmit {
+            self.transmit(data)?;
+        }
+        self.lines.push_back(response.reply);
+        Ok(())
+    }
+
+    fn recv_line(&mut self, timeout: Duration) -> io::Result<Option<String>> {
+        let start_time = Instant::now();
+        loop {
+            self.receive()?;
+            if let Some(line) = self.lines.pop_front() {
+                return Ok(Some(line));
+            }
+            if start_time.elapsed() >= timeout {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A fresh UID in the same 24 hex digit shape as real modules.
+pub fn random_uid() -> String {
+    let mut uid = String::new();
+    while uid.len() < UID_LEN {
+        let random = RandomState::new().build_hasher().finish();
+        uid.push_str(&format!("{:016X}", random));
+    }
+    uid.truncate(UID_LEN);
+    uid
+}
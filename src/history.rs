@@ -0,0 +1,233 @@
+use crate::node::{self, Message};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// How much history to keep. Zero means no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Retention {
+    pub max_age_days: u64,
+    pub max_per_conversation: usize,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_age_days: 90,
+            max_per_conversation: 1000,
+        }
+    }
+}
+
+/// Conversations on disk, as an append-only log with one RON record per line.
+///
+/// Every change to a message appends a fresh snapshot of it, and the last snapshot of a
+/// message wins when the log is read back. The log is rewritten without superseded or
+/// expired records when the retention policy is set on startup, and whenever it changes.
+pub struct History {
+    path: PathBuf,
+    file: Option<File>,
+    retention: Retention,
+}
+
+/// The history shared between the node and the UI.
+pub type SharedHistory = Arc<Mutex<History>>;
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Record {
+    peer: String,
+    message: Message,
+}
+
+impl History {
+    /// Read the log at `path` into conversations keyed by peer.
+    ///
+    /// Nothing is dropped until [`set_retention`](Self::set_retention) says what to
+    /// keep, as the policy in force is only known once the settings are loaded.
+    pub fn open(path: PathBuf) -> (Self, HashMap<String, Vec<Message>>) {
+        let mut conversations: HashMap<String, Vec<Message>> = HashMap::new();
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines() {
+                let Ok(line) = line else { break };
+                // A crash mid-write can leave a torn last line
+                let Ok(record) = ron::from_str::<Record>(&line) else {
+                    eprintln!("Skipping unreadable history record in {}", path.display());
+                    continue;
+                };
+                let conversation = conversations.entry(record.peer).or_default();
+                let message = record.message;
+                match conversation
+                    .iter_mut()
+                    .find(|old| old.is(&message.sender, message.id, message.time))
+                {
+                    Some(old) => *old = message,
+                    None => conversation.push(message),
+                }
+            }
+        }
+        let history = Self {
+            path,
+            file: None,
+            retention: Retention::default(),
+        };
+        (history, conversations)
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Change the policy and drop whatever it no longer allows, in memory and on disk.
+    pub fn set_retention(
+        &mut self,
+        retention: Retention,
+        conversations: &mut HashMap<String, Vec<Message>>,
+    ) {
+        self.retention = retention;
+        self.compact(conversations);
+    }
+
+    /// Append the current state of `message` in the conversation with `peer`.
+    pub fn record(&mut self, peer: &str, message: &Message) {
+        let record = Record {
+            peer: peer.to_string(),
+            message: message.clone(),
+        };
+        if let Err(err) = self.append(&record) {
+            eprintln!("Failed to save message history: {}", err);
+        }
+    }
+
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.file = Some(file);
+        }
+        let line = ron::to_string(record)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let file = self.file.as_mut().unwrap();
+        writeln!(file, "{}", line)
+    }
+
+    /// Apply the retention policy to `conversations` and rewrite the log to match.
+    fn compact(&mut self, conversations: &mut HashMap<String, Vec<Message>>) {
+        let now = node::now();
+        let Retention {
+            max_age_days,
+            max_per_conversation,
+        } = self.retention;
+        for conversation in conversations.values_mut() {
+            if max_age_days > 0 {
+                conversation.retain(|message| message.time + max_age_days * 86400 >= now);
+            }
+            if max_per_conversation > 0 && conversation.len() > max_per_conversation {
+                conversation.drain(..conversation.len() - max_per_conversation);
+            }
+        }
+        conversations.retain(|_, conversation| !conversation.is_empty());
+
+        if let Err(err) = self.rewrite(conversations) {
+            eprintln!("Failed to compact message history: {}", err);
+        }
+    }
+
+    fn rewrite(&mut self, conversations: &HashMap<String, Vec<Message>>) -> io::Result<()> {
+        self.file = None;
+        let temp = self.path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        for (peer, conversation) in conversations {
+            for message in conversation {
+                let record = Record {
+                    peer: peer.clone(),
+                    message: message.clone(),
+                };
+                let line = ron::to_string(&record)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                writeln!(file, "{}", line)?;
+            }
+        }
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::DeliveryState;
+    use crate::protocol::{self, MessageId};
+
+    const PEER: &str = "002E0051044A7EE1000026BF";
+
+    /// Message `id` to the peer, sent at `time`.
+    fn message(id: MessageId, body: &str, time: u64) -> Message {
+        Message {
+            id: Some(id),
+            sender: "002E0051044A7EE1000026C0".to_string(),
+            recipient: PEER.to_string(),
+            data: body.to_string(),
+            time,
+            state: DeliveryState::Sent,
+            count: 1,
+            ttl: protocol::DEFAULT_TTL,
+            hops: 0,
+            parts: 0,
+            parts_acked: 0,
+        }
+    }
+
+    #[test]
+    fn history_survives_restart_with_latest_state() {
+        let path =
+            std::env::temp_dir().join(format!("lora_mesh_history_{}.log", std::process::id()));
+        let old = message(2, "hello, world", 1_000_000_000);
+        let mut message = message(1, "hello, world", node::now());
+
+        let (mut history, _) = History::open(path.clone());
+        history.record(PEER, &old);
+        history.record(PEER, &message);
+        message.state = DeliveryState::Delivered;
+        message.count = 2;
+        history.record(PEER, &message);
+
+        let (mut history, mut conversations) = History::open(path.clone());
+        history.set_retention(Retention::default(), &mut conversations);
+        let conversation = &conversations[PEER];
+        assert_eq!(conversation.len(), 1, "expired message should be dropped");
+        assert_eq!(conversation[0].data, "hello, world");
+        assert_eq!(conversation[0].state, DeliveryState::Delivered);
+        assert_eq!(conversation[0].count, 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn history_is_kept_by_the_configured_retention_across_restarts() {
+        let path = std::env::temp_dir().join(format!(
+            "lora_mesh_history_forever_{}.log",
+            std::process::id()
+        ));
+        let old = message(1, "from long ago", node::now() - 200 * 86400);
+        let (mut history, _) = History::open(path.clone());
+        history.record(PEER, &old);
+        drop(history);
+
+        let keep_everything = Retention {
+            max_age_days: 0,
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            // Opening the log drops nothing before the saved policy is applied
+            let (mut history, mut conversations) = History::open(path.clone());
+            assert_eq!(conversations[PEER].len(), 1);
+            history.set_retention(keep_everything, &mut conversations);
+            assert_eq!(conversations[PEER][0].data, "from long ago");
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
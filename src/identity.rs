@@ -0,0 +1,210 @@
+use crate::protocol::{self, Frame};
+use crate::storage;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::Key;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// This installation's long-term keys: Ed25519 to sign, X25519 to agree on secrets.
+pub struct Identity {
+    signing: SigningKey,
+    exchange: StaticSecret,
+}
+
+/// The public half of a peer's [`Identity`], as carried in an announcement.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PublicIdentity {
+    verifying: VerifyingKey,
+    exchange: PublicKey,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct StoredIdentity {
+    signing: String,
+    exchange: String,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            signing: SigningKey::generate(&mut OsRng),
+            exchange: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    /// Load the identity saved at `path`, creating and saving a new one on first run.
+    pub fn load_or_generate(path: &Path) -> Self {
+        if let Some(stored) = storage::load::<StoredIdentity>(path) {
+            let signing = decode_array(&stored.signing);
+            let exchange = decode_array(&stored.exchange);
+            if let (Some(signing), Some(exchange)) = (signing, exchange) {
+                return Self {
+                    signing: SigningKey::from_bytes(&signing),
+                    exchange: StaticSecret::from(exchange),
+                };
+            }
+            eprintln!(
+                "Identity in {} is damaged, making a new one",
+                path.display()
+            );
+        }
+        let identity = Self::generate();
+        let stored = StoredIdentity {
+            signing: STANDARD_NO_PAD.encode(identity.signing.to_bytes()),
+            exchange: STANDARD_NO_PAD.encode(identity.exchange.to_bytes()),
+        };
+        if let Err(err) = storage::save(path, &stored) {
+            eprintln!("Failed to save identity: {}", err);
+        }
+        identity
+    }
+
+    pub fn public(&self) -> PublicIdentity {
+        PublicIdentity {
+            verifying: self.signing.verifying_key(),
+            exchange: PublicKey::from(&self.exchange),
+        }
+    }
+
+    /// A signed frame binding our public keys to `uid`, which at most `ttl` relays pass
+    /// on.
+    pub fn announcement(&self, uid: &str, time: u64, ttl: u8) -> Frame {
+        let mut frame = Frame::Announce {
+            ttl,
+            hops: 0,
+            sender: uid.to_string(),
+            time,
+            public_key: self.public().encode(),
+            signature: String::new(),
+        };
+        let signature = self.signing.sign(protocol::encode(&frame).as_bytes());
+        if let Frame::Announce {
+            signature: field, ..
+        } = &mut frame
+        {
+            *field = STANDARD_NO_PAD.encode(signature.to_bytes());
+        }
+        frame
+    }
+
+    /// A signed presence beacon from `uid`, which at most `ttl` relays pass on.
+    pub fn beacon(&self, uid: &str, time: u64, ttl: u8) -> Frame {
+        let mut frame = Frame::Beacon {
+            ttl,
+            hops: 0,
+            time,
+            sender: uid.to_string(),
+            signature: String::new(),
+        };
+        let signature = self.signing.sign(protocol::encode(&frame).as_bytes());
+        if let Frame::Beacon {
+            signature: field, ..
+        } = &mut frame
+        {
+            *field = STANDARD_NO_PAD.encode(signature.to_bytes());
+        }
+        frame
+    }
+
+    /// The message key for `own_uid` and `peer_uid`, from an X25519 exchange with `peer`.
+    pub fn shared_key(&self, own_uid: &str, peer_uid: &str, peer: &PublicIdentity) -> Key {
+        let shared = self.exchange.diffie_hellman(&peer.exchange);
+        let (low, high) = if own_uid <= peer_uid {
+            (own_uid, peer_uid)
+        } else {
+            (peer_uid, own_uid)
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(b"lora_mesh x25519 key\0");
+        hasher.update(shared.as_bytes());
+        hasher.update(low.as_bytes());
+        hasher.update(high.as_bytes());
+        hasher.finalize()
+    }
+}
+
+impl PublicIdentity {
+    /// Base64 of the verifying key followed by the exchange key.
+    pub fn encode(&self) -> String {
+        let mut bytes = self.verifying.to_bytes().to_vec();
+        bytes.extend_from_slice(self.exchange.as_bytes());
+        STANDARD_NO_PAD.encode(bytes)
+    }
+
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let bytes: [u8; 64] = decode_array(encoded)?;
+        let (verifying, exchange) = bytes.split_at(32);
+        Some(Self {
+            verifying: VerifyingKey::from_bytes(verifying.try_into().ok()?).ok()?,
+            exchange: PublicKey::from(<[u8; 32]>::try_from(exchange).ok()?),
+        })
+    }
+
+    /// A short digest for comparing keys out of band, e.g. `3F2A 91C0 77B1 04DE`.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(self.encode().as_bytes());
+        digest[..8]
+            .chunks(2)
+            .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Check an announcement's signature and return the keys it vouches for.
+pub fn verify_announcement(frame: &Frame) -> Option<PublicIdentity> {
+    let Frame::Announce {
+        public_key,
+        signature,
+        ..
+    } = frame
+    else {
+        return None;
+    };
+    let public = PublicIdentity::decode(public_key)?;
+    let signature = Signature::from_bytes(&decode_array(signature)?);
+    let mut unsigned = frame.as_sent();
+    if let Frame::Announce {
+        signature: field, ..
+    } = &mut unsigned
+    {
+        field.clear();
+    }
+    public
+        .verifying
+        .verify(protocol::encode(&unsigned).as_bytes(), &signature)
+        .ok()?;
+    Some(public)
+}
+
+/// Check a beacon's signature against the keys pinned for its sender.
+pub fn verify_beacon(frame: &Frame, public: &PublicIdentity) -> bool {
+    let Frame::Beacon { signature, .. } = frame else {
+        return false;
+    };
+    let Some(signature) = decode_array(signature) else {
+        return false;
+    };
+    let mut unsigned = frame.as_sent();
+    if let Frame::Beacon {
+        signature: field, ..
+    } = &mut unsigned
+    {
+        field.clear();
+    }
+    public
+        .verifying
+        .verify(
+            protocol::encode(&unsigned).as_bytes(),
+            &Signature::from_bytes(&signature),
+        )
+        .is_ok()
+}
+
+fn decode_array<const N: usize>(encoded: &str) -> Option<[u8; N]> {
+    STANDARD_NO_PAD.decode(encoded).ok()?.try_into().ok()
+}
@@ -1,4 +1,24 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 pub mod app;
+pub mod channels;
+pub mod contacts;
+pub mod crypto;
+pub mod dedupe;
+pub mod emulator;
+pub mod history;
+pub mod identity;
+pub mod mailbox;
+pub mod neighbors;
+pub mod node;
+pub mod outbox;
+pub mod presence;
+pub mod protocol;
+pub mod reassembly;
+pub mod replay;
+pub mod routing;
+pub mod sim;
+pub mod storage;
+pub mod transfer;
+pub mod transport;
 pub use app::TemplateApp;
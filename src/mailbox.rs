@@ -0,0 +1,214 @@
+use crate::protocol::{Frame, MessageId};
+use crate::storage;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+/// Whether to hold messages for stations that are out of reach, and how many.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct MailboxPolicy {
+    pub enabled: bool,
+    /// Bytes of held frames at most; the oldest are dropped first.
+    pub quota_bytes: usize,
+    /// Held frames and failed messages are given up on after this long.
+    pub expiry_hours: u64,
+}
+
+impl Default for MailboxPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            quota_bytes: 16 * 1024,
+            expiry_hours: 24,
+        }
+    }
+}
+
+/// A relayed message kept for its recipient.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+struct Held {
+    recipient: String,
+    sender: String,
+    id: MessageId,
+    /// The frame as we passed it on
+    payload: String,
+    /// UNIX time it was stored
+    stored: u64,
+}
+
+/// Messages we relayed, kept until their recipient is heard again or a confirmation
+/// for them goes by.
+///
+/// A station out of range when a message was flooded misses it for good otherwise.
+/// Keeping a copy at every relay costs a little storage, and whichever relay hears the
+/// recipient first hands it over.
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    /// Oldest first
+    held: VecDeque<Held>,
+    policy: MailboxPolicy,
+    path: Option<PathBuf>,
+}
+
+impl Mailbox {
+    /// Load the frames saved at `path` and save them there whenever they change.
+    pub fn persist(&mut self, path: PathBuf) {
+        self.held = storage::load(&path).unwrap_or_default();
+        self.path = Some(path);
+    }
+
+    pub fn policy(&self) -> MailboxPolicy {
+        self.policy
+    }
+
+    /// Change the policy and drop whatever it no longer allows.
+    pub fn set_policy(&mut self, policy: MailboxPolicy, now: u64) {
+        self.policy = policy;
+        if !policy.enabled {
+            self.held.clear();
+        }
+        self.trim(now);
+        self.save();
+    }
+
+    /// Frames held.
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// Bytes held, counted against the quota.
+    pub fn bytes(&self) -> usize {
+        self.held.iter().map(|held| held.payload.len()).sum()
+    }
+
+    /// Whether something sent at `time` is too old to be worth delivering at `now`.
+    pub fn expired(&self, time: u64, now: u64) -> bool {
+        time + self.policy.expiry_hours * 3600 < now
+    }
+
+    /// Keep `frame`, encoded as `payload`, for its recipient. Only data frames with an
+    /// ID are held, as only those can be matched to their confirmation.
+    pub fn hold(&mut self, frame: &Frame, payload: String, now: u64) {
+        let Frame::Data {
+            id: Some(id),
+            recipient,
+            sender,
+            ..
+        } = frame
+        else {
+            return;
+        };
+        if !self.policy.enabled || payload.len() > self.policy.quota_bytes {
+            return;
+        }
+        self.held
+            .retain(|held| !(held.sender == *sender && held.id == *id));
+        self.held.push_back(Held {
+            recipient: recipient.clone(),
+            sender: sender.clone(),
+            id: *id,
+            payload,
+            stored: now,
+        });
+        self.trim(now);
+        self.save();
+    }
+
+    /// Take out everything held for `recipient`, now that it has been heard.
+    pub fn release(&mut self, recipient: &str, now: u64) -> Vec<String> {
+        self.trim(now);
+        let (released, kept): (Vec<Held>, Vec<Held>) = self
+            .held
+            .drain(..)
+            .partition(|held| held.recipient == recipient);
+        self.held = kept.into();
+        if !released.is_empty() {
+            self.save();
+        }
+        released.into_iter().map(|held| held.payload).collect()
+    }
+
+    /// Drop message `id` from `sender` to `recipient`, as the recipient has confirmed it.
+    pub fn delivered(&mut self, sender: &str, recipient: &str, id: MessageId) {
+        let before = self.held.len();
+        self.held.retain(|held| {
+            !(held.sender == sender && held.recipient == recipient && held.id == id)
+        });
+        if self.held.len() != before {
+            self.save();
+        }
+    }
+
+    /// Drop expired frames, then the oldest ones beyond the quota.
+    fn trim(&mut self, now: u64) {
+        let expiry = self.policy.expiry_hours * 3600;
+        self.held.retain(|held| held.stored + expiry >= now);
+        let mut bytes = self.bytes();
+        while bytes > self.policy.quota_bytes {
+            let Some(oldest) = self.held.pop_front() else {
+                break;
+            };
+            bytes -= oldest.payload.len();
+        }
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            if let Err(err) = storage::save(path, &self.held) {
+                eprintln!("Failed to save held messages: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+
+    #[test]
+    fn mailbox_keeps_to_quota_and_expiry() {
+        let frame = |id| Frame::Data {
+            id: Some(id),
+            ttl: protocol::DEFAULT_TTL,
+            hops: 1,
+            next: None,
+            recipient: "002E0051044A7EE1000026BF".to_string(),
+            sender: "002E0051044A7EE1000026C0".to_string(),
+            time: 1_700_000_000,
+            body: "x".repeat(100),
+        };
+        let size = protocol::encode(&frame(0)).len();
+        let mut mailbox = Mailbox::default();
+        mailbox.set_policy(
+            MailboxPolicy {
+                enabled: true,
+                quota_bytes: 2 * size,
+                expiry_hours: 1,
+            },
+            0,
+        );
+        let now = 1_700_000_000;
+        for id in 0..3 {
+            let frame = frame(id);
+            mailbox.hold(&frame, protocol::encode(&frame), now);
+        }
+        assert_eq!(mailbox.len(), 2);
+        // Only a confirmation from the recipient counts
+        mailbox.delivered("002E0051044A7EE1000026C0", "002E0051044A7EE1000026C1", 2);
+        assert_eq!(mailbox.len(), 2);
+        mailbox.delivered("002E0051044A7EE1000026C0", "002E0051044A7EE1000026BF", 2);
+        assert_eq!(mailbox.len(), 1);
+
+        let released = mailbox.release("002E0051044A7EE1000026BF", now + 60);
+        assert_eq!(released, vec![protocol::encode(&frame(1))]);
+        mailbox.hold(&frame(3), protocol::encode(&frame(3)), now);
+        assert!(mailbox
+            .release("002E0051044A7EE1000026BF", now + 7200)
+            .is_empty());
+    }
+}
@@ -1,24 +1,30 @@
 #![warn(clippy::all, rust_2018_idioms)]
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 // hide console window on Windows in release
-use lora_mesh::app::Message;
-use serialport::{self, available_ports, SerialPort};
+use lora_mesh::emulator::EmulatedRadio;
+use lora_mesh::history::History;
+use lora_mesh::identity::Identity;
+use lora_mesh::node::{self, Node, SharedMessages};
+use lora_mesh::storage;
+use lora_mesh::transport::{SerialTransport, SharedTransport, Transport};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Duration, Instant};
 
 // When compiling natively:
 #[cfg(not(target_arch = "wasm32"))]
 fn main() -> eframe::Result<()> {
-    let serial_port = Arc::new(Mutex::new(open_serial_port()));
-    let shared_messages: Arc<Mutex<HashMap<String, Vec<Message>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let userid = get_username(serial_port.clone());
-    let target_user = Arc::new(Mutex::new(std::option::Option::Some(
-        "002E0051044A7EE1000026BF".to_string(),
-    )));
-    let seen_messages: Arc<Mutex<Vec<(String, u64)>>> = Arc::new(Mutex::new(Vec::new()));
+    let transport: SharedTransport = Arc::new(Mutex::new(open_transport()));
+    let (history, conversations) = match storage::data_file("history.log") {
+        Some(path) => {
+            let (history, conversations) = History::open(path);
+            (Some(Arc::new(Mutex::new(history))), conversations)
+        }
+        None => (None, HashMap::new()),
+    };
+    let shared_messages: SharedMessages = Arc::new(Mutex::new(conversations));
+    let userid = get_username(transport.clone());
 
     if let Some(name) = userid.clone() {
         println!("{}", name);
@@ -26,12 +32,37 @@ fn main() -> eframe::Result<()> {
         println!("No userid found");
     }
 
-    start_serial_read_thread(
-        serial_port.clone(),
-        shared_messages.clone(),
+    let node = Arc::new(Mutex::new(Node::new(
         userid.clone().unwrap(),
-        seen_messages.clone(),
-    );
+        transport,
+        shared_messages,
+    )));
+    {
+        let mut node = node.lock().unwrap();
+        let keyring = node.keyring();
+        let mut keyring = keyring.lock().unwrap();
+        if let Some(path) = storage::data_file("identity.ron") {
+            keyring.set_identity(Identity::load_or_generate(&path));
+        }
+        if let Some(path) = storage::data_file("peers.ron") {
+            keyring.persist_peers(path);
+        }
+        drop(keyring);
+        if let Some(path) = storage::data_file("replay.ron") {
+            node.persist_replay(path);
+        }
+        if let Some(path) = storage::data_file("mailbox.ron") {
+            node.persist_mailbox(path);
+        }
+        if let Some(history) = history {
+            node.set_history(history);
+        }
+        if node.announce().is_err() {
+            eprintln!("Error writing to port");
+        }
+    }
+    node::spawn(node.clone());
+    node::spawn_outbox(node.clone());
 
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
 
@@ -49,15 +80,7 @@ fn main() -> eframe::Result<()> {
     eframe::run_native(
         "lora_mesh",
         native_options,
-        Box::new(|cc| {
-            Box::new(lora_mesh::TemplateApp::new(
-                cc,
-                shared_messages,
-                serial_port,
-                userid,
-                target_user,
-            ))
-        }),
+        Box::new(|cc| Box::new(lora_mesh::TemplateApp::new(cc, node))),
     )
 }
 
@@ -81,223 +104,31 @@ fn main() {
     });
 }
 
-fn open_serial_port() -> Option<Box<dyn SerialPort>> {
-    let port_name = (|| -> Result<String, Box<dyn std::error::Error>> {
-        let ports = available_ports()?;
-        for p in ports {
-            match p.port_type {
-                serialport::SerialPortType::UsbPort(usb_info) => {
-                    // Many Arduinos have a VID of 0x2341 and PIDs of 0x0042 or 0x0043
-                    if usb_info.vid == 0x2341 && (usb_info.pid == 0x0042 || usb_info.pid == 0x0043)
-                    {
-                        return Ok(p.port_name);
-                    }
-                }
-                serialport::SerialPortType::PciPort => {
-                    eprintln!("Haven't implemented handling Pci Devices")
-                }
-                serialport::SerialPortType::BluetoothPort => {
-                    eprintln!("Haven't implemented handling Bluetooth Devices")
-                }
-                serialport::SerialPortType::Unknown => {
-                    eprintln!("Haven't implemented handling unknown devices")
-                }
-            }
-        }
-        Err("No suitable port found".into())
-    })()
-    .ok()?;
-
-    serialport::new(port_name, 9600)
-        .timeout(Duration::from_millis(10))
-        .open()
-        .map_err(|e| {
-            eprintln!("Failed to open serial port: {}", e);
-        })
-        .ok()
-}
-
-fn start_serial_read_thread(
-    ownable_serial_port: Arc<Mutex<Option<Box<dyn SerialPort>>>>,
-    messages_for_thread: Arc<Mutex<HashMap<String, Vec<Message>>>>,
-    userid: String,
-    ownable_seen_messages: Arc<Mutex<Vec<(String, u64)>>>,
-) {
-    thread::spawn(move || loop {
-        let mut serial_buf: Vec<u8> = vec![0; 300];
-        let mut received_str = String::new();
-
-        match ownable_serial_port.lock() {
-            Ok(mut lock) => {
-                if let Some(port) = lock.as_mut() {
-                    let start_time = Instant::now();
-                    loop {
-                        let t = port.read(&mut serial_buf);
-                        match t {
-                            Ok(count) => {
-                                received_str
-                                    .push_str(&String::from_utf8_lossy(&serial_buf[..count]));
-                                if received_str.ends_with("\r\n") {
-                                    break;
-                                } else if start_time.elapsed() > Duration::from_millis(500) {
-                                    // If more than 500ms has passed, break the loop
-                                    break;
-                                } else {
-                                    thread::sleep(Duration::from_millis(25));
-                                    continue;
-                                }
-                            }
-                            Err(_err) => {
-                                break;
-                            }
-                        }
-                    }
-                    match ownable_seen_messages.lock() {
-                        Ok(mut seen_messages) => {
-                            if let Some(start) = received_str.find("+RCV=") {
-                                let data_parts: Vec<&str> =
-                                    received_str[start..].split(',').collect();
-                                if !(seen_messages.contains(&(
-                                    data_parts[0].to_string() + data_parts[1] + data_parts[2],
-                                    data_parts[2][9..19].parse().unwrap_or_default(),
-                                )) || seen_messages.contains(&(
-                                    data_parts[0].to_string() + data_parts[1] + data_parts[2],
-                                    data_parts[2][48..58].parse().unwrap_or_default(),
-                                ))) {
-                                    if data_parts[2][..9].contains("CONFIRMED") {
-                                        seen_messages.push((
-                                            data_parts[0].to_string()
-                                                + data_parts[1]
-                                                + data_parts[2],
-                                            data_parts[2][9..19].parse().unwrap_or_default(),
-                                        ));
-                                    } else {
-                                        seen_messages.push((
-                                            data_parts[0].to_string()
-                                                + data_parts[1]
-                                                + data_parts[2],
-                                            data_parts[2][48..58].parse().unwrap_or_default(),
-                                        ));
-                                    }
-                                    if received_str.contains(&userid) {
-                                        if let Ok(mut messages) = messages_for_thread.lock() {
-                                            if data_parts[2][..9].contains("CONFIRMED") {
-                                                // Find the message using the senders address and mark the message as confirmed
-                                                if let Some(messages_vec) = messages
-                                                    .get_mut(&data_parts[2][43..67].to_string())
-                                                {
-                                                    for message in messages_vec.iter_mut() {
-                                                        if message.time
-                                                            == data_parts[2][9..19]
-                                                                .parse()
-                                                                .unwrap_or_default()
-                                                        {
-                                                            message.confirmed = true;
-                                                        }
-                                                    }
-                                                }
-                                            } else {
-                                                messages
-                                                    .entry(data_parts[2][24..48].to_string())
-                                                    .or_insert_with(Vec::new)
-                                                    .push(Message {
-                                                        recipient: data_parts[2][..24].to_string(),
-                                                        sender: data_parts[2][24..48].to_string(),
-                                                        time: data_parts[2][48..58]
-                                                            .parse()
-                                                            .unwrap_or_default(),
-                                                        data: data_parts[2][58..].to_string(),
-                                                        confirmed: true,
-                                                        count: 1,
-                                                    });
-
-                                                send_message(
-                                                    "CONFIRMED".to_string(),
-                                                    67,
-                                                    data_parts[2][24..48].to_string(),
-                                                    data_parts[2][..24].to_string(),
-                                                    data_parts[2][48..58].to_string(),
-                                                    "".to_string(),
-                                                    port,
-                                                );
-                                            }
-                                        }
-                                    } else {
-                                        if received_str[4..14].contains("CONFIRMED") {
-                                            send_message(
-                                                "CONFIRMED".to_string(),
-                                                data_parts[1].parse().unwrap(),
-                                                data_parts[2][24..48].to_string(),
-                                                data_parts[2][48..72].to_string(),
-                                                data_parts[2][14..24].to_string(),
-                                                "".to_string(),
-                                                port,
-                                            );
-                                        } else {
-                                            if received_str.len() > 58 {
-                                                send_message(
-                                                    "SEND".to_string(),
-                                                    data_parts[1].parse().unwrap(),
-                                                    data_parts[2][..24].to_string(),
-                                                    data_parts[2][24..48].to_string(),
-                                                    data_parts[2][48..58].to_string(),
-                                                    data_parts[2][58..].to_string(),
-                                                    port,
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            let mut to_remove = Vec::new();
-
-                            for (index, message) in seen_messages.iter().enumerate() {
-                                if SystemTime::now()
-                                    .duration_since(SystemTime::UNIX_EPOCH)
-                                    .unwrap()
-                                    .as_secs()
-                                    > message.1 + 5
-                                {
-                                    to_remove.push(index);
-                                }
-                            }
-
-                            for index in to_remove.iter().rev() {
-                                seen_messages.remove(*index);
-                            }
-                        }
-                        Err(poisoned) => {
-                            eprintln!("Mutex was poisoned. Inner error: {:?}", poisoned);
-                        }
-                    }
-                } else {
-                    //Don't print because it will spam the console
-                    //eprintln!("Serial port is not available");
-                }
-            }
-            Err(poisoned) => {
-                eprintln!("Mutex was poisoned. Inner error: {:?}", poisoned);
-            }
+/// The attached board, or an emulated module when started with `--emulate`.
+fn open_transport() -> Box<dyn Transport> {
+    if std::env::args().any(|arg| arg == "--emulate") {
+        // Set LORA_MESH_UID to keep the same identity between runs
+        match EmulatedRadio::open(std::env::var("LORA_MESH_UID").ok()) {
+            Ok(radio) => return Box::new(radio),
+            Err(err) => eprintln!("Failed to start the emulated module: {}", err),
         }
-
-        thread::sleep(Duration::from_millis(25));
-    });
+    }
+    Box::new(SerialTransport::open())
 }
 
-fn get_username(ownable_serial_port: Arc<Mutex<Option<Box<dyn SerialPort>>>>) -> Option<String> {
+fn get_username(transport: SharedTransport) -> Option<String> {
     (|| -> Result<String, Box<dyn std::error::Error>> {
-        let mut lock = ownable_serial_port.lock()?;
-        if let Some(port) = lock.as_mut() {
-            thread::sleep(Duration::from_millis(2000));
-            let mut serial_buf: Vec<u8> = vec![0; 240];
-            port.write(format!("AT+UID?\r\n").as_bytes())?;
-            thread::sleep(Duration::from_millis(100));
-            let t = port.read(serial_buf.as_mut_slice())?;
-            let received_str = String::from_utf8_lossy(&serial_buf[..t]);
-            if let Some(start) = received_str.find("+UID=") {
-                let data_parts: Vec<&str> = received_str[start..].split('=').collect();
-                if data_parts.len() == 2 {
-                    return Ok(data_parts[1].trim().to_string());
+        let mut transport = transport.lock()?;
+        thread::sleep(Duration::from_millis(2000));
+        transport.send_line("AT+UID?")?;
+        let start_time = Instant::now();
+        while start_time.elapsed() < Duration::from_millis(1000) {
+            if let Some(received_str) = transport.recv_line(Duration::from_millis(100))? {
+                if let Some(start) = received_str.find("+UID=") {
+                    let data_parts: Vec<&str> = received_str[start..].split('=').collect();
+                    if data_parts.len() == 2 {
+                        return Ok(data_parts[1].trim().to_string());
+                    }
                 }
             }
         }
@@ -305,36 +136,3 @@ fn get_username(ownable_serial_port: Arc<Mutex<Option<Box<dyn SerialPort>>>>) ->
     })()
     .ok()
 }
-
-fn send_message(
-    command_type: String,
-    input_len: i32,
-    recipient: String,
-    sender: String,
-    time_stamp: String,
-    input: String,
-    serial_port: &mut Box<dyn SerialPort>,
-) {
-    let command: String;
-    if command_type == "CONFIRMED" {
-        command = format!(
-            "AT+SEND=0,{},CONFIRMED{}{}{}\r\n",
-            input_len, time_stamp, recipient, sender
-        );
-    } else if command_type == "SEND" {
-        command = format!(
-            "AT+SEND=0,{},{}{}{}{}\r\n",
-            input.trim().len() + 58,
-            recipient,
-            sender,
-            time_stamp,
-            input.trim()
-        );
-    } else {
-        eprintln!("Invalid command type");
-        return;
-    }
-    if let Err(_) = serial_port.write(command.as_bytes()) {
-        eprintln!("Error writing to port");
-    }
-}
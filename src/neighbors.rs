@@ -0,0 +1,68 @@
+use crate::protocol::Reception;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// A station we have heard directly, without a relay in between.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbor {
+    /// `AT+ADDRESS` of its module
+    pub address: u16,
+    /// UNIX time of the last packet heard from it
+    pub last_seen: u64,
+    /// Signal strength of that packet in dBm
+    pub rssi: i32,
+    /// Signal to noise ratio of that packet in dB
+    pub snr: i32,
+    /// Packets heard from it
+    pub packets: u64,
+}
+
+/// Stations in radio range, by UID.
+///
+/// Only frames with hop counts tell a direct transmission from a relayed one, so
+/// stations that send nothing else (old clients, or ones only announcing themselves)
+/// don't show up.
+#[derive(Debug, Clone, Default)]
+pub struct NeighborTable {
+    neighbors: BTreeMap<String, Neighbor>,
+}
+
+/// The neighbor table shared between the node and the UI.
+pub type SharedNeighbors = Arc<Mutex<NeighborTable>>;
+
+impl NeighborTable {
+    pub fn get(&self, uid: &str) -> Option<&Neighbor> {
+        self.neighbors.get(uid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Neighbor)> {
+        self.neighbors.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.neighbors.is_empty()
+    }
+
+    /// Count a packet heard from `uid` at `now`.
+    pub fn record(&mut self, uid: &str, reception: &Reception<'_>, now: u64) {
+        let neighbor = self
+            .neighbors
+            .entry(uid.to_string())
+            .or_insert_with(|| Neighbor {
+                address: reception.address,
+                last_seen: now,
+                rssi: reception.rssi,
+                snr: reception.snr,
+                packets: 0,
+            });
+        neighbor.address = reception.address;
+        neighbor.last_seen = now;
+        neighbor.rssi = reception.rssi;
+        neighbor.snr = reception.snr;
+        neighbor.packets += 1;
+    }
+}
@@ -0,0 +1,1379 @@
+use crate::crypto::{self, CryptoError, Keyring, Pin, SharedKeyring};
+use crate::dedupe::{DedupeCache, DedupePolicy, DedupeStats};
+use crate::history::SharedHistory;
+use crate::identity;
+use crate::mailbox::{Mailbox, MailboxPolicy};
+use crate::neighbors::SharedNeighbors;
+use crate::outbox::{Outbox, RetryPolicy};
+use crate::presence::{PresencePolicy, SharedPresence};
+use crate::protocol::{self, Frame, MessageId, Reception};
+use crate::reassembly::{Assembly, Progress, Reassembler, ReassemblyPolicy};
+use crate::replay::{Replay, ReplayGuard};
+use crate::routing::{Route, RoutePolicy, RoutingTable};
+use crate::transfer::{self, TransferError};
+use crate::transport::{SharedTransport, Transport};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::Key;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Shortest time between two announcements sent because a peer sealed a frame with a
+/// key we don't take from it.
+const KEY_REMINDER_GAP: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Message {
+    /// Missing on messages from older clients
+    #[serde(default)]
+    pub id: Option<MessageId>,
+    pub sender: String,
+    pub recipient: String,
+    pub data: String, // Message Contents
+    pub time: u64,    // UNIX Epoch time
+    pub state: DeliveryState,
+    /// Times we have transmitted it, for our own messages
+    pub count: u64,
+    /// How many relays may pass it on
+    #[serde(default = "default_ttl")]
+    pub ttl: u8,
+    /// How many relays passed it on, for messages we received
+    #[serde(default)]
+    pub hops: u8,
+    /// Fragments our message is sent in, 0 if it fits one packet
+    #[serde(default)]
+    pub parts: u8,
+    /// How many of those the recipient said it has
+    #[serde(default)]
+    pub parts_acked: u8,
+}
+
+fn default_ttl() -> u8 {
+    protocol::DEFAULT_TTL
+}
+
+impl Message {
+    /// Whether this is message `id` from `sender`. Without an ID, the send time has to do.
+    pub fn is(&self, sender: &str, id: Option<MessageId>, time: u64) -> bool {
+        self.sender == sender
+            && match (self.id, id) {
+                (Some(own), Some(id)) => own == id,
+                (None, None) => self.time == time,
+                _ => false,
+            }
+    }
+}
+
+/// How far one of our messages has got. Received messages are always `Delivered`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum DeliveryState {
+    /// Waiting for the radio to take it.
+    Queued,
+    /// Transmitted once, waiting for a confirmation.
+    Sent,
+    /// Transmitted again because no confirmation came.
+    Retrying,
+    /// Confirmed by the recipient.
+    #[default]
+    Delivered,
+    /// Still unconfirmed after the last retry.
+    Failed,
+}
+
+/// Conversations keyed by the peer's UID, shared with the UI.
+pub type SharedMessages = Arc<Mutex<HashMap<String, Vec<Message>>>>;
+
+/// The mesh logic of one station: delivery, confirmation and relaying.
+pub struct Node {
+    userid: String,
+    transport: SharedTransport,
+    messages: SharedMessages,
+    keyring: SharedKeyring,
+    seen: DedupeCache,
+    neighbors: SharedNeighbors,
+    routes: RoutingTable,
+    mailbox: Mailbox,
+    reassembly: Reassembler,
+    presence: SharedPresence,
+    /// When our last beacon went out
+    last_beacon: Option<Instant>,
+    /// When we last announced because a frame from a peer didn't open
+    last_key_reminder: Option<Instant>,
+    replay: ReplayGuard,
+    history: Option<SharedHistory>,
+    outbox: Outbox,
+    /// Destinations our messages went out to without a route, to ask for one
+    route_requests: VecDeque<String>,
+    /// Frames the mailbox released for a peer that came back, waiting for their slot
+    released: VecDeque<String>,
+}
+
+impl Node {
+    /// Our unfinished messages already in `messages`, e.g. from history, are queued again.
+    pub fn new(userid: String, transport: SharedTransport, messages: SharedMessages) -> Self {
+        let mut outbox = Outbox::default();
+        for (peer, conversation) in messages.lock().unwrap().iter() {
+            for message in conversation {
+                let unfinished = matches!(
+                    message.state,
+                    DeliveryState::Queued | DeliveryState::Sent | DeliveryState::Retrying
+                );
+                // Messages saved before IDs existed cannot be matched to a confirmation
+                if let (Some(id), true) = (message.id, message.sender == userid && unfinished) {
+                    outbox.push(peer, id, message.count);
+                }
+            }
+        }
+        Self {
+            userid,
+            transport,
+            messages,
+            keyring: Arc::new(Mutex::new(Keyring::default())),
+            seen: DedupeCache::default(),
+            neighbors: SharedNeighbors::default(),
+            routes: RoutingTable::default(),
+            mailbox: Mailbox::default(),
+            reassembly: Reassembler::default(),
+            presence: SharedPresence::default(),
+            last_beacon: None,
+            last_key_reminder: None,
+            replay: ReplayGuard::default(),
+            history: None,
+            outbox,
+            route_requests: VecDeque::new(),
+            released: VecDeque::new(),
+        }
+    }
+
+    pub fn userid(&self) -> &str {
+        &self.userid
+    }
+
+    pub fn messages(&self) -> SharedMessages {
+        self.messages.clone()
+    }
+
+    pub fn transport(&self) -> SharedTransport {
+        self.transport.clone()
+    }
+
+    pub fn keyring(&self) -> SharedKeyring {
+        self.keyring.clone()
+    }
+
+    /// Stations heard directly, with the signal of the last packet from each.
+    pub fn neighbors(&self) -> SharedNeighbors {
+        self.neighbors.clone()
+    }
+
+    /// The current route to `destination`, if we know one.
+    pub fn route_to(&self, destination: &str) -> Option<Route> {
+        self.routes.get(destination, Instant::now()).cloned()
+    }
+
+    /// Every route still current, by destination.
+    pub fn routes(&self) -> Vec<(String, Route)> {
+        let now = Instant::now();
+        self.routes
+            .iter()
+            .filter_map(|(destination, _)| {
+                let route = self.routes.get(destination, now)?;
+                Some((destination.clone(), route.clone()))
+            })
+            .collect()
+    }
+
+    pub fn set_route_policy(&mut self, policy: RoutePolicy) {
+        self.routes.set_policy(policy);
+    }
+
+    /// Messages held for stations out of reach.
+    pub fn mailbox(&self) -> &Mailbox {
+        &self.mailbox
+    }
+
+    pub fn set_mailbox_policy(&mut self, policy: MailboxPolicy) {
+        self.mailbox.set_policy(policy, now());
+        // Whatever is held or retried until it expires must still get past the guard
+        self.replay.set_window(policy.expiry_hours * 3600);
+    }
+
+    /// Split messages partly received so far.
+    pub fn incoming(&self) -> Vec<Progress> {
+        self.reassembly.in_progress(Instant::now())
+    }
+
+    pub fn set_reassembly_policy(&mut self, policy: ReassemblyPolicy) {
+        self.reassembly.set_policy(policy);
+    }
+
+    /// When each station was last heard from, to tell who is online.
+    pub fn presence(&self) -> SharedPresence {
+        self.presence.clone()
+    }
+
+    pub fn set_presence_policy(&mut self, policy: PresencePolicy) {
+        self.presence.lock().unwrap().set_policy(policy);
+    }
+
+    /// Keep held messages in `path` so they survive a restart.
+    pub fn persist_mailbox(&mut self, path: PathBuf) {
+        self.mailbox.persist(path);
+    }
+
+    /// Keep the replay windows in `path` so they survive a restart.
+    pub fn persist_replay(&mut self, path: PathBuf) {
+        self.replay.persist(path);
+    }
+
+    /// Save the replay windows now rather than when they are next due, e.g. on exit.
+    pub fn flush_replay(&mut self) {
+        self.replay.flush(now());
+    }
+
+    /// Log every change to a conversation to `history`.
+    pub fn set_history(&mut self, history: SharedHistory) {
+        self.history = Some(history);
+    }
+
+    pub fn history(&self) -> Option<SharedHistory> {
+        self.history.clone()
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.outbox.set_policy(policy);
+    }
+
+    pub fn set_dedupe_policy(&mut self, policy: DedupePolicy) {
+        self.seen.set_policy(policy);
+    }
+
+    /// How many repeated frames were dropped, and how many forgotten early.
+    pub fn dedupe_stats(&self) -> DedupeStats {
+        self.seen.stats()
+    }
+
+    /// Add a chat message to the conversation with `recipient` and queue it for sending.
+    pub fn send_message(&mut self, recipient: &str, body: &str) -> MessageId {
+        self.send_message_with_ttl(recipient, body, protocol::DEFAULT_TTL)
+    }
+
+    /// Like [`send_message`](Self::send_message), letting at most `ttl` relays pass the
+    /// message on.
+    pub fn send_message_with_ttl(&mut self, recipient: &str, body: &str, ttl: u8) -> MessageId {
+        let id = self.queue_message(recipient, body, ttl);
+        self.flush_outbox();
+        id
+    }
+
+    /// Add our message to the conversation with `recipient` and the outbox, leaving it
+    /// to [`flush_outbox`](Self::flush_outbox) to send.
+    fn queue_message(&mut self, recipient: &str, body: &str, ttl: u8) -> MessageId {
+        let id = OsRng.next_u32();
+        let message = Message {
+            id: Some(id),
+            sender: self.userid.clone(),
+            recipient: recipient.to_string(),
+            data: body.to_string(),
+            time: now(),
+            state: DeliveryState::Queued,
+            count: 0,
+            ttl: ttl.min(protocol::MAX_TTL),
+            hops: 0,
+            parts: 0,
+            parts_acked: 0,
+        };
+        self.record(recipient, &message);
+        self.outbox.push(recipient, id, 0);
+        self.messages
+            .lock()
+            .unwrap()
+            .entry(recipient.to_string())
+            .or_default()
+            .push(message);
+        id
+    }
+
+    /// Send file `name`, holding `bytes`, to `recipient` as a message per chunk. Each
+    /// is confirmed and retried on its own, so retrying the failed ones resumes the
+    /// transfer. The chunks are only queued here, for the outbox to send at its pace.
+    /// Returns the IDs of the chunk messages.
+    pub fn send_file(
+        &mut self,
+        recipient: &str,
+        name: &str,
+        bytes: &[u8],
+    ) -> Result<Vec<MessageId>, TransferError> {
+        self.send_file_with_ttl(recipient, name, bytes, protocol::DEFAULT_TTL)
+    }
+
+    /// Like [`send_file`](Self::send_file), letting at most `ttl` relays pass it on.
+    pub fn send_file_with_ttl(
+        &mut self,
+        recipient: &str,
+        name: &str,
+        bytes: &[u8],
+        ttl: u8,
+    ) -> Result<Vec<MessageId>, TransferError> {
+        let chunks = transfer::split(name, bytes)?;
+        Ok(chunks
+            .iter()
+            .map(|chunk| self.queue_message(recipient, &chunk.encode(), ttl))
+            .collect())
+    }
+
+    /// Start over with one of our failed messages to `peer`. Returns whether there was one.
+    pub fn retry(&mut self, peer: &str, id: MessageId) -> bool {
+        let messages = self.messages.clone();
+        let mut messages = messages.lock().unwrap();
+        let message = messages.get_mut(peer).and_then(|conversation| {
+            conversation.iter_mut().find(|message| {
+                message.sender == self.userid
+                    && message.id == Some(id)
+                    && message.state == DeliveryState::Failed
+            })
+        });
+        let Some(message) = message else {
+            return false;
+        };
+        message.state = DeliveryState::Queued;
+        self.record(peer, message);
+        drop(messages);
+        self.outbox.push(peer, id, 0);
+        self.flush_outbox();
+        true
+    }
+
+    /// Transmit the next frame of a queued message or of those the mailbox released,
+    /// resend those whose confirmation is overdue and mark those out of retries as
+    /// failed.
+    ///
+    /// At most one frame goes out per call, and none before the retry policy's frame
+    /// gap has passed since the last, so a split message takes a call per fragment.
+    pub fn flush_outbox(&mut self) {
+        let now = Instant::now();
+        let policy = self.outbox.policy();
+        if !self.outbox.ready(now) {
+            return;
+        }
+        // Asked for by an earlier message, in a slot of its own
+        while let Some(destination) = self.route_requests.pop_front() {
+            if self.request_route(&destination) {
+                self.outbox.sent(now);
+                return;
+            }
+        }
+        // Held for others, before our own, as they have waited longer
+        if let Some(payload) = self.released.pop_front() {
+            if self.send_payload(&payload).is_err() {
+                eprintln!("Error writing to port");
+            }
+            self.outbox.sent(now);
+            return;
+        }
+        let messages = self.messages.clone();
+        while let Some(mut pending) = self.outbox.take_next(now) {
+            let mut messages = messages.lock().unwrap();
+            let message = messages.get_mut(&pending.peer).and_then(|conversation| {
+                conversation
+                    .iter_mut()
+                    .find(|message| message.sender == self.userid && message.id == Some(pending.id))
+            });
+            // Gone means dropped by the retention policy
+            let Some(message) = message else {
+                continue;
+            };
+            if matches!(
+                message.state,
+                DeliveryState::Delivered | DeliveryState::Failed
+            ) {
+                continue;
+            }
+            let channel = self.keyring.lock().unwrap().is_channel(&message.recipient);
+            let Some(frames) = message_frames(&self.keyring.lock().unwrap(), message) else {
+                eprintln!("Can't send message to {}: too long", message.recipient);
+                message.state = DeliveryState::Failed;
+                self.record(&pending.peer, message);
+                continue;
+            };
+            if pending.unsent == 0 {
+                // A fresh transmission of the message
+                if pending.attempts >= policy.max_attempts {
+                    eprintln!("No confirmation from {}, giving up", message.recipient);
+                    message.state = DeliveryState::Failed;
+                    self.record(&pending.peer, message);
+                    continue;
+                }
+                if pending.attempts > 0 {
+                    // The route may have broken; flood the retry and look for a new one
+                    self.routes.forget(&message.recipient);
+                    // The recipient may have given up on the fragments it had by now
+                    pending.acked = 0;
+                }
+                if frames.len() > 1 {
+                    message.parts = frames.len() as u8;
+                }
+                // Only the fragments not confirmed yet. With all of them confirmed, the
+                // confirmation of the whole must have been lost, and any one brings it back.
+                let all = u64::MAX >> (64 - frames.len());
+                pending.unsent = match all & !pending.acked {
+                    0 => 1 << (frames.len() - 1),
+                    unsent => unsent,
+                };
+            }
+            let index = pending.unsent.trailing_zeros() as usize;
+            let mut frame = frames[index].clone();
+            let routed = self.route(&mut frame);
+            let due = match self.send_payload(&protocol::encode(&frame)) {
+                Ok(()) => {
+                    self.outbox.sent(now);
+                    pending.unsent &= !(1 << index);
+                    if pending.unsent != 0 {
+                        // The rest follow on the next calls
+                        self.outbox.schedule(pending, now);
+                        return;
+                    }
+                    if channel {
+                        // Nobody confirms a message to a channel, so it is done once sent
+                        message.count = 1;
+                        message.state = DeliveryState::Delivered;
+                        self.record(&pending.peer, message);
+                        return;
+                    }
+                    if !routed {
+                        self.route_requests.push_back(message.recipient.clone());
+                    }
+                    pending.attempts += 1;
+                    message.count = pending.attempts;
+                    message.state = if pending.attempts == 1 {
+                        DeliveryState::Sent
+                    } else {
+                        DeliveryState::Retrying
+                    };
+                    self.record(&pending.peer, message);
+                    now + policy.delay(pending.attempts)
+                }
+                Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+                    // Resending won't make it any shorter
+                    eprintln!("Can't send message to {}: {}", message.recipient, err);
+                    message.state = DeliveryState::Failed;
+                    self.record(&pending.peer, message);
+                    continue;
+                }
+                Err(_) => {
+                    eprintln!("Error writing to port");
+                    now + policy.write_error_delay
+                }
+            };
+            self.outbox.schedule(pending, due);
+            return;
+        }
+    }
+
+    /// Wait up to `timeout` for one line from the radio and handle it.
+    ///
+    /// Returns `Ok(false)` if nothing arrived.
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<bool> {
+        let line = self.transport.lock().unwrap().recv_line(timeout)?;
+        match line {
+            Some(line) => {
+                self.handle_line(&line);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Handle one line printed by the radio module.
+    pub fn handle_line(&mut self, line: &str) {
+        // Anything that isn't `+RCV=` is a reply to one of our own commands
+        let Ok(reception) = protocol::receive(line) else {
+            return;
+        };
+        let payload = reception.payload;
+        match protocol::decode(payload) {
+            Ok(frame) => {
+                self.learn_routes(&frame, &reception);
+                let transmitter = frame.transmitter().map(str::to_string);
+                self.handle_frame(frame, payload);
+                // After the frame, so a confirmation it carries is taken into account
+                if let Some(transmitter) = transmitter {
+                    self.peer_heard(&transmitter);
+                }
+            }
+            Err(err) => eprintln!("Dropping malformed frame: {}", err),
+        }
+    }
+
+    /// Note who is in range from what `frame` tells about the station that sent it our
+    /// way, and for route frames, which way their sender lies.
+    fn learn_routes(&mut self, frame: &Frame, reception: &Reception<'_>) {
+        let Some(transmitter) = frame.transmitter() else {
+            return;
+        };
+        if transmitter == self.userid {
+            return;
+        }
+        let now = Instant::now();
+        self.neighbors
+            .lock()
+            .unwrap()
+            .record(transmitter, reception, self::now());
+        self.routes.learn(transmitter, transmitter, 0, now);
+        let sender = frame.sender();
+        if let Frame::RouteRequest { .. } | Frame::RouteReply { .. } = frame {
+            if sender != self.userid {
+                self.routes.learn(sender, transmitter, frame.hops(), now);
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: Frame, payload: &str) {
+        // A routed frame is none of our business unless it was handed to us or is for
+        // us. Not remembering it lets a flooded retry through if the route broke.
+        let handed_to_us = frame.next().map_or(true, |next| next == self.userid);
+        if !handed_to_us && frame.recipient() != self.userid {
+            return;
+        }
+        // Ours are remembered once their seal or tag checked out, so a forgery with the
+        // same ID can't get the genuine frame dropped as a duplicate
+        let key = seen_key(&frame, payload);
+        let verified_later = frame.recipient() == self.userid
+            && matches!(
+                frame,
+                Frame::Data { .. } | Frame::Confirmation { .. } | Frame::FragmentAck { .. }
+            );
+        let new = if verified_later {
+            self.seen.check(&key, Instant::now())
+        } else {
+            self.seen.insert(key, Instant::now())
+        };
+        if !new {
+            return;
+        }
+        // We can't check the tag of a confirmation between others, but one without any
+        // can be sent by anyone and so clears nothing
+        if let Frame::Confirmation {
+            id: Some(id),
+            recipient,
+            sender,
+            tag: Some(_),
+            ..
+        } = &frame
+        {
+            self.mailbox.delivered(recipient, sender, *id);
+        }
+
+        if frame.sender() == self.userid {
+            // One of our own frames coming back from a relay
+            return;
+        }
+        // Relays change the hop counts, so compare frames as their sender sent them
+        let sent = match frame.id() {
+            Some(_) => protocol::encode(&frame.as_sent()),
+            None => payload.to_string(),
+        };
+        let replay = self.replay.check(&frame, &sent).err();
+        let channel = self.keyring.lock().unwrap().is_channel(frame.recipient());
+        if let Frame::RouteRequest { .. } | Frame::RouteReply { .. } = frame {
+            self.handle_route(frame, payload);
+            return;
+        }
+        if let Frame::Announce { .. } = frame {
+            if !self.learn_identity(&frame) {
+                return;
+            }
+            self.learn_presence(&frame);
+        } else if let Frame::Beacon { .. } = frame {
+            if !self.learn_presence(&frame) {
+                return;
+            }
+        } else if frame.recipient() == self.userid {
+            self.deliver(frame, &sent, replay);
+            return;
+        } else if channel {
+            // Everyone in the channel wants it, so it goes on once we have read it. It
+            // is never retried, so one seen before is played back and goes no further.
+            let replayed = replay.is_some();
+            self.deliver(frame.clone(), &sent, replay);
+            if replayed {
+                return;
+            }
+        } else if replay == Some(Replay::Stale) {
+            eprintln!(
+                "Not relaying frame from {}: {}",
+                frame.sender(),
+                Replay::Stale
+            );
+            return;
+        }
+        // A repeat from outside the seen window may be the sender retrying an
+        // unconfirmed message, which only reaches its recipient through us
+
+        // A channel is never heard from, so nothing held for it would be handed over
+        if let (false, Some(held)) = (channel, frame.relayed(&self.userid, None)) {
+            self.mailbox.hold(&held, protocol::encode(&held), now());
+        }
+
+        // The relay a routed frame was handed to floods it if it knows no way further
+        let next = match frame.next() {
+            Some(_) => self
+                .routes
+                .get(frame.recipient(), Instant::now())
+                .map(|route| route.next_hop.clone()),
+            None => None,
+        };
+        self.relay(&frame, payload, next);
+    }
+
+    /// Hand `peer` what we kept for it, now that it is reachable: relayed messages in
+    /// the mailbox, and our own that failed.
+    fn peer_heard(&mut self, peer: &str) {
+        if !self.mailbox.policy().enabled {
+            return;
+        }
+        let now = now();
+        // Sent at the outbox's pace, as a burst would overrun the radio
+        self.released.extend(self.mailbox.release(peer, now));
+        let failed: Vec<MessageId> = self
+            .messages
+            .lock()
+            .unwrap()
+            .get(peer)
+            .into_iter()
+            .flatten()
+            .filter(|message| {
+                message.sender == self.userid
+                    && message.state == DeliveryState::Failed
+                    && !self.mailbox.expired(message.time, now)
+            })
+            .filter_map(|message| message.id)
+            .collect();
+        for id in failed {
+            self.retry(peer, id);
+        }
+    }
+
+    /// Pass `frame` (received as `payload`) on to `next`, or to everyone, with one more
+    /// hop counted.
+    fn relay(&mut self, frame: &Frame, payload: &str, next: Option<String>) {
+        let Some(relayed) = frame.relayed(&self.userid, next) else {
+            eprintln!(
+                "Not relaying frame from {}: hop limit reached",
+                frame.sender()
+            );
+            return;
+        };
+        // Frames in the old layout go on exactly as they came
+        let relayed = match relayed {
+            Frame::Data { id: None, .. } | Frame::Confirmation { id: None, .. } => {
+                payload.to_string()
+            }
+            _ => protocol::encode(&relayed),
+        };
+        if self.send_payload(&relayed).is_err() {
+            eprintln!("Error writing to port");
+        }
+    }
+
+    /// Answer a route request for us or flood it on, and pass route replies back
+    /// toward the station that asked. The routes they carry are learned on receipt.
+    fn handle_route(&mut self, frame: Frame, payload: &str) {
+        let now = Instant::now();
+        match &frame {
+            Frame::RouteRequest {
+                id,
+                ttl,
+                hops,
+                recipient,
+                sender,
+                ..
+            } if *recipient == self.userid => {
+                let Some(route) = self.routes.get(sender, now) else {
+                    return;
+                };
+                let reply = Frame::RouteReply {
+                    id: *id,
+                    ttl: ttl.saturating_add(*hops),
+                    hops: 0,
+                    time: self::now(),
+                    recipient: sender.clone(),
+                    sender: self.userid.clone(),
+                    next: route.next_hop.clone(),
+                    last_hop: self.userid.clone(),
+                };
+                if self.send_payload(&protocol::encode(&reply)).is_err() {
+                    eprintln!("Error writing to port");
+                }
+            }
+            Frame::RouteRequest { .. } => self.relay(&frame, payload, None),
+            Frame::RouteReply {
+                recipient, next, ..
+            } if *next == self.userid && *recipient != self.userid => {
+                match self.routes.get(recipient, now) {
+                    Some(route) => {
+                        let next = route.next_hop.clone();
+                        self.relay(&frame, payload, Some(next));
+                    }
+                    None => eprintln!("Dropping route reply: no way back to {}", recipient),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Hand `frame` to the next hop toward its recipient, if we know one. Returns
+    /// whether we did; otherwise the frame is flooded.
+    fn route(&self, frame: &mut Frame) -> bool {
+        let route = self.routes.get(frame.recipient(), Instant::now());
+        match frame {
+            Frame::Data {
+                id: Some(_), next, ..
+            }
+            | Frame::Confirmation {
+                id: Some(_), next, ..
+            }
+            | Frame::Fragment { next, .. }
+            | Frame::FragmentAck { next, .. } => {
+                *next = route.map(|route| route.next_hop.clone());
+                next.is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Flood a request for a route to `destination`, unless one went out recently.
+    /// Returns whether one went out.
+    fn request_route(&mut self, destination: &str) -> bool {
+        if !self.routes.should_request(destination, Instant::now()) {
+            return false;
+        }
+        let request = Frame::RouteRequest {
+            id: OsRng.next_u32(),
+            ttl: protocol::DEFAULT_TTL,
+            hops: 0,
+            time: now(),
+            recipient: destination.to_string(),
+            sender: self.userid.clone(),
+            last_hop: self.userid.clone(),
+        };
+        if self.send_payload(&protocol::encode(&request)).is_err() {
+            eprintln!("Error writing to port");
+        }
+        true
+    }
+
+    /// Handle a frame addressed to us, whose wire form as its sender sent it is `sent`.
+    /// `replay` says why it was seen before, if it was.
+    fn deliver(&mut self, frame: Frame, sent: &str, replay: Option<Replay>) {
+        let seen = seen_key(&frame, sent);
+        match frame {
+            Frame::Confirmation {
+                id,
+                ttl,
+                hops,
+                time,
+                recipient,
+                sender,
+                tag,
+                ..
+            } => {
+                let keys = self
+                    .keyring
+                    .lock()
+                    .unwrap()
+                    .candidate_keys(&recipient, &sender);
+                // Without any shared key there is nothing to check the tag against
+                if !keys.is_empty() {
+                    let unsigned = Frame::Confirmation {
+                        id,
+                        ttl,
+                        hops,
+                        next: None,
+                        time,
+                        recipient: recipient.clone(),
+                        sender: sender.clone(),
+                        tag: None,
+                    };
+                    let unsigned = protocol::encode(&unsigned.as_sent());
+                    let authentic = tag
+                        .as_deref()
+                        .is_some_and(|tag| crypto::verify_tag(&keys, &unsigned, tag));
+                    if !authentic {
+                        eprintln!("Ignoring unauthenticated confirmation from {}", sender);
+                        return;
+                    }
+                }
+                self.seen.insert(seen, Instant::now());
+                if let Some(replay) = replay {
+                    eprintln!("Ignoring confirmation from {}: {}", sender, replay);
+                    return;
+                }
+                self.replay.record(&sender, time, sent, now());
+                // Find the message using the senders address and mark the message as confirmed
+                let mut messages = self.messages.lock().unwrap();
+                if let Some(messages_vec) = messages.get_mut(&sender) {
+                    for message in messages_vec.iter_mut() {
+                        if message.is(&recipient, id, time)
+                            && message.state != DeliveryState::Delivered
+                        {
+                            message.state = DeliveryState::Delivered;
+                            message.parts_acked = message.parts;
+                            self.record(&sender, message);
+                        }
+                    }
+                }
+            }
+            Frame::Data {
+                id,
+                ttl,
+                hops,
+                recipient,
+                sender,
+                time,
+                body,
+                ..
+            } => {
+                let (keys, channel) = {
+                    let keyring = self.keyring.lock().unwrap();
+                    (
+                        keyring.candidate_keys(&recipient, &sender),
+                        keyring.is_channel(&recipient),
+                    )
+                };
+                // Channel messages go in the channel's thread, the rest in the sender's
+                let peer = if channel { &recipient } else { &sender }.clone();
+                let opened = if keys.is_empty() && crypto::is_sealed(&body) {
+                    Err(CryptoError::NoKey)
+                } else if keys.is_empty() {
+                    Ok((body, None))
+                } else {
+                    let header = protocol::data_header(id, &recipient, &sender, time);
+                    crypto::open_with_any(&keys, &header, &body)
+                        .map(|(text, key)| (text, Some(key)))
+                };
+                let (body, key) = match opened {
+                    Ok(opened) => {
+                        self.seen.insert(seen, Instant::now());
+                        opened
+                    }
+                    Err(err) => {
+                        eprintln!("Rejected message from {}: {}", sender, err);
+                        // The sender may be missing our key, or not know we have its own
+                        if !channel {
+                            self.remind_of_key();
+                        }
+                        return;
+                    }
+                };
+                if replay.is_none() {
+                    self.replay.record(&sender, time, sent, now());
+                }
+                // Give the confirmation the same reach the message was given
+                let ttl = ttl.saturating_add(hops);
+                // Tag with the key the sender used, which it is sure to hold
+                let mut confirmation =
+                    confirmation(id, &sender, &recipient, time, ttl, key.as_ref());
+                self.route(&mut confirmation);
+                let confirmation = protocol::encode(&confirmation);
+                // A retry of a message we have, e.g. sealed again under a fresh nonce
+                let known = id.is_some() && self.knows(&peer, &sender, id, time);
+                let replay = replay.or(known.then_some(Replay::Duplicate));
+                if let Some(replay) = replay {
+                    eprintln!("Ignoring message from {}: {}", sender, replay);
+                    // The sender may be retrying because our confirmation got lost
+                    if !channel
+                        && replay == Replay::Duplicate
+                        && self.send_payload(&confirmation).is_err()
+                    {
+                        eprintln!("Error writing to port");
+                    }
+                    return;
+                }
+                let message = Message {
+                    id,
+                    recipient,
+                    sender: sender.clone(),
+                    time,
+                    data: body,
+                    state: DeliveryState::Delivered,
+                    count: 0,
+                    ttl,
+                    hops,
+                    parts: 0,
+                    parts_acked: 0,
+                };
+                self.record(&peer, &message);
+                self.messages
+                    .lock()
+                    .unwrap()
+                    .entry(peer)
+                    .or_default()
+                    .push(message);
+                if !channel && self.send_payload(&confirmation).is_err() {
+                    eprintln!("Error writing to port");
+                }
+            }
+            Frame::Fragment {
+                id,
+                ttl,
+                hops,
+                ref recipient,
+                ref sender,
+                time,
+                ..
+            } => {
+                if replay == Some(Replay::Stale) {
+                    eprintln!("Ignoring fragment from {}: {}", sender, Replay::Stale);
+                    return;
+                }
+                let channel = self.keyring.lock().unwrap().is_channel(recipient);
+                // Fragments of a message we have: our confirmation must have been lost
+                if !channel && self.knows(sender, sender, Some(id), time) {
+                    let key = self
+                        .keyring
+                        .lock()
+                        .unwrap()
+                        .candidate_keys(recipient, sender)
+                        .into_iter()
+                        .next();
+                    let ttl = ttl.saturating_add(hops);
+                    let mut confirmation =
+                        confirmation(Some(id), sender, recipient, time, ttl, key.as_ref());
+                    self.route(&mut confirmation);
+                    if self.send_payload(&protocol::encode(&confirmation)).is_err() {
+                        eprintln!("Error writing to port");
+                    }
+                    return;
+                }
+                match self.reassembly.insert(&frame, Instant::now()) {
+                    Some(Assembly::Complete(data)) => {
+                        let sent = protocol::encode(&data.as_sent());
+                        let replay = self.replay.check(&data, &sent).err();
+                        self.deliver(data, &sent, replay);
+                    }
+                    Some(Assembly::Incomplete {
+                        received,
+                        ack: true,
+                    }) if !channel => {
+                        // Which key the message is sealed with shows once it is whole
+                        let key = self
+                            .keyring
+                            .lock()
+                            .unwrap()
+                            .candidate_keys(recipient, sender)
+                            .into_iter()
+                            .next();
+                        let ttl = ttl.saturating_add(hops);
+                        let mut ack =
+                            fragment_ack(id, sender, recipient, time, ttl, received, key.as_ref());
+                        self.route(&mut ack);
+                        if self.send_payload(&protocol::encode(&ack)).is_err() {
+                            eprintln!("Error writing to port");
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Frame::FragmentAck {
+                id,
+                ttl,
+                hops,
+                time,
+                recipient,
+                sender,
+                received,
+                tag,
+                ..
+            } => {
+                let keys = self
+                    .keyring
+                    .lock()
+                    .unwrap()
+                    .candidate_keys(&recipient, &sender);
+                // Checked like a confirmation's, as a forged one would hold retries back
+                if !keys.is_empty() {
+                    let ttl = ttl.saturating_add(hops);
+                    let unsigned = protocol::encode(&fragment_ack(
+                        id, &recipient, &sender, time, ttl, received, None,
+                    ));
+                    let authentic = tag
+                        .as_deref()
+                        .is_some_and(|tag| crypto::verify_tag(&keys, &unsigned, tag));
+                    if !authentic {
+                        eprintln!("Ignoring unauthenticated fragment ack from {}", sender);
+                        return;
+                    }
+                }
+                self.seen.insert(seen, Instant::now());
+                let Some(acked) = self.outbox.acknowledge(&sender, id, received) else {
+                    return;
+                };
+                let mut messages = self.messages.lock().unwrap();
+                let message = messages.get_mut(&sender).and_then(|conversation| {
+                    conversation
+                        .iter_mut()
+                        .find(|message| message.sender == self.userid && message.id == Some(id))
+                });
+                if let Some(message) = message {
+                    message.parts_acked = acked.count_ones() as u8;
+                    self.record(&sender, message);
+                }
+            }
+            Frame::Announce { .. }
+            | Frame::Beacon { .. }
+            | Frame::RouteRequest { .. }
+            | Frame::RouteReply { .. } => {}
+        }
+    }
+
+    /// Whether the conversation with `peer` has message `id` from `sender`, sent at `time`.
+    fn knows(&self, peer: &str, sender: &str, id: Option<MessageId>, time: u64) -> bool {
+        self.messages
+            .lock()
+            .unwrap()
+            .get(peer)
+            .is_some_and(|conversation| {
+                conversation
+                    .iter()
+                    .any(|message| message.is(sender, id, time))
+            })
+    }
+
+    /// Pin the keys in a valid announcement. Returns whether it is worth relaying.
+    fn learn_identity(&mut self, frame: &Frame) -> bool {
+        let Some(public) = identity::verify_announcement(frame) else {
+            eprintln!("Bad signature on announcement from {}", frame.sender());
+            return false;
+        };
+        let pin = self.keyring.lock().unwrap().pin(frame.sender(), public);
+        match pin {
+            Pin::New => {
+                println!("Learned the public key of {}", frame.sender());
+                // Let the newcomer learn ours in turn
+                if self.announce().is_err() {
+                    eprintln!("Error writing to port");
+                }
+            }
+            Pin::Known => {}
+            Pin::Conflict => {
+                eprintln!(
+                    "Ignoring announcement from {}: key differs from the pinned one",
+                    frame.sender()
+                );
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Note the sender of a signed announcement or beacon as present, and hand it what
+    /// we kept for it. Returns false for a beacon with a bad signature, which isn't
+    /// worth relaying.
+    fn learn_presence(&mut self, frame: &Frame) -> bool {
+        if let Frame::Beacon { .. } = frame {
+            let keyring = self.keyring.lock().unwrap();
+            match keyring.peer(frame.sender()) {
+                Some(public) if !identity::verify_beacon(frame, public) => {
+                    eprintln!("Bad signature on beacon from {}", frame.sender());
+                    return false;
+                }
+                Some(_) => {}
+                // Stations further on may have its key
+                None => return true,
+            }
+        }
+        let fresh = self
+            .presence
+            .lock()
+            .unwrap()
+            .heard(frame.sender(), frame.time(), now());
+        if fresh {
+            self.peer_heard(frame.sender());
+        }
+        true
+    }
+
+    /// Broadcast a signed beacon saying we are on the air.
+    pub fn send_beacon(&mut self) -> io::Result<()> {
+        let ttl = self.presence.lock().unwrap().policy().ttl;
+        let frame = self.keyring.lock().unwrap().identity().beacon(
+            &self.userid,
+            now(),
+            ttl.min(protocol::MAX_TTL),
+        );
+        self.last_beacon = Some(Instant::now());
+        self.send_payload(&protocol::encode(&frame))
+    }
+
+    /// Send a beacon if the interval since the last one is up.
+    pub fn beacon_if_due(&mut self) {
+        let interval = self.presence.lock().unwrap().policy().interval_secs;
+        if interval == 0 {
+            return;
+        }
+        let due = self
+            .last_beacon
+            .map_or(true, |last| last.elapsed() >= Duration::from_secs(interval));
+        if due && self.send_beacon().is_err() {
+            eprintln!("Error writing to port");
+        }
+    }
+
+    /// Announce our keys again after a frame from a peer didn't open, unless we did so
+    /// lately.
+    ///
+    /// A peer that missed our announcement seals with a passphrase key, or none, which
+    /// we don't take from it once its own key is pinned. Our announcement gets it to pin
+    /// ours and answer with its own, after which both use the pair key.
+    fn remind_of_key(&mut self) {
+        let due = self
+            .last_key_reminder
+            .map_or(true, |last| last.elapsed() >= KEY_REMINDER_GAP);
+        if due {
+            self.last_key_reminder = Some(Instant::now());
+            if self.announce().is_err() {
+                eprintln!("Error writing to port");
+            }
+        }
+    }
+
+    /// Broadcast our signed public keys.
+    pub fn announce(&mut self) -> io::Result<()> {
+        let frame = self.keyring.lock().unwrap().identity().announcement(
+            &self.userid,
+            now(),
+            protocol::DEFAULT_TTL,
+        );
+        self.send_payload(&protocol::encode(&frame))
+    }
+
+    fn record(&self, peer: &str, message: &Message) {
+        if let Some(history) = &self.history {
+            history.lock().unwrap().record(peer, message);
+        }
+    }
+
+    fn send_payload(&self, payload: &str) -> io::Result<()> {
+        send_payload(self.transport.lock().unwrap().as_mut(), payload)
+    }
+}
+
+/// The frame for one of our messages, sealing the body when we share a key with its
+/// recipient.
+pub fn data_frame(keyring: &Keyring, message: &Message) -> Frame {
+    sealed_frame(keyring, message, crypto::seal)
+}
+
+/// The frames to send one of our messages in: the data frame, or its fragments if it
+/// is too long for one packet. `None` if it is too long for [`MAX_FRAGMENTS`] of them.
+///
+/// [`MAX_FRAGMENTS`]: protocol::MAX_FRAGMENTS
+pub fn message_frames(keyring: &Keyring, message: &Message) -> Option<Vec<Frame>> {
+    let frame = data_frame(keyring, message);
+    if protocol::fragment(&frame)?.len() == 1 {
+        return Some(vec![frame]);
+    }
+    // Sealed the same way every time, so fragments sent in later rounds fit together
+    protocol::fragment(&sealed_frame(keyring, message, crypto::seal_repeatably))
+}
+
+fn sealed_frame(
+    keyring: &Keyring,
+    message: &Message,
+    seal: fn(&Key, &str, &str) -> String,
+) -> Frame {
+    let Message {
+        id,
+        sender,
+        recipient,
+        time,
+        ttl,
+        ..
+    } = message;
+    let body = match keyring.pair_key(sender, recipient) {
+        Some(key) => {
+            let header = protocol::data_header(*id, recipient, sender, *time);
+            seal(&key, &header, &message.data)
+        }
+        None => message.data.clone(),
+    };
+    Frame::Data {
+        id: *id,
+        ttl: *ttl,
+        hops: 0,
+        next: None,
+        recipient: recipient.clone(),
+        sender: sender.clone(),
+        time: *time,
+        body,
+    }
+}
+
+/// Confirm message `id` that `recipient` sent us at `time`, tagged with `key` if we
+/// have one.
+fn confirmation(
+    id: Option<MessageId>,
+    recipient: &str,
+    sender: &str,
+    time: u64,
+    ttl: u8,
+    key: Option<&Key>,
+) -> Frame {
+    let frame = Frame::Confirmation {
+        id,
+        ttl,
+        hops: 0,
+        next: None,
+        time,
+        recipient: recipient.to_string(),
+        sender: sender.to_string(),
+        tag: None,
+    };
+    tagged(frame, key)
+}
+
+/// Our ack to `recipient` of the fragments in `received` of its message `id`, sent at
+/// `time`, tagged with `key` if we share one.
+fn fragment_ack(
+    id: MessageId,
+    recipient: &str,
+    sender: &str,
+    time: u64,
+    ttl: u8,
+    received: u64,
+    key: Option<&Key>,
+) -> Frame {
+    let frame = Frame::FragmentAck {
+        id,
+        ttl,
+        hops: 0,
+        next: None,
+        time,
+        recipient: recipient.to_string(),
+        sender: sender.to_string(),
+        received,
+        tag: None,
+    };
+    tagged(frame, key)
+}
+
+/// An untagged confirmation or fragment ack, with a MAC under `key` added if given.
+fn tagged(mut frame: Frame, key: Option<&Key>) -> Frame {
+    let Some(key) = key else {
+        return frame;
+    };
+    let tag = crypto::tag(key, &protocol::encode(&frame));
+    if let Frame::Confirmation { tag: field, .. } | Frame::FragmentAck { tag: field, .. } =
+        &mut frame
+    {
+        *field = Some(tag);
+    }
+    frame
+}
+
+/// What identifies `frame` among recently seen ones: its kind, sender and ID (the time
+/// for beacons), with the part of the message for fragments and their acks, or for
+/// frames from older clients the whole payload.
+fn seen_key(frame: &Frame, payload: &str) -> String {
+    match frame {
+        Frame::Data { id: Some(id), .. } => format!("M{:08X}{}", id, frame.sender()),
+        // With the tag, so a forged confirmation or ack can't shut out the real one at
+        // relays
+        Frame::Confirmation {
+            id: Some(id), tag, ..
+        } => format!(
+            "A{:08X}{}{}",
+            id,
+            frame.sender(),
+            tag.as_deref().unwrap_or_default()
+        ),
+        Frame::RouteRequest { id, .. } => format!("Q{:08X}{}", id, frame.sender()),
+        Frame::RouteReply { id, .. } => format!("R{:08X}{}", id, frame.sender()),
+        Frame::Fragment { id, index, .. } => {
+            format!("F{:08X}{:02X}{}", id, index, frame.sender())
+        }
+        Frame::FragmentAck {
+            id, received, tag, ..
+        } => format!(
+            "K{:08X}{:016X}{}{}",
+            id,
+            received,
+            frame.sender(),
+            tag.as_deref().unwrap_or_default()
+        ),
+        // With the signature, so a forged beacon or announcement can't shut out the
+        // real one
+        Frame::Beacon {
+            time, signature, ..
+        } => format!("B{}{}{}", time, frame.sender(), signature),
+        Frame::Announce {
+            time, signature, ..
+        } => format!("N{}{}{}", time, frame.sender(), signature),
+        _ => payload.to_string(),
+    }
+}
+
+/// Hand a frame to the radio for broadcast.
+pub fn send_payload(transport: &mut dyn Transport, payload: &str) -> io::Result<()> {
+    let command = protocol::send_command(payload)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    transport.send_line(&command)
+}
+
+/// Run `node` on a background thread, reconnecting the radio when it drops out.
+pub fn spawn(node: Arc<Mutex<Node>>) -> thread::JoinHandle<()> {
+    let mut last_reconnect = Instant::now();
+    thread::spawn(move || loop {
+        match node.lock() {
+            Ok(mut node) => match node.poll(Duration::from_millis(10)) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotConnected => {
+                    //Don't print because it will spam the console
+                    if last_reconnect.elapsed() > Duration::from_secs(2) {
+                        last_reconnect = Instant::now();
+                        if node.transport.lock().unwrap().reconnect().is_ok() {
+                            println!("Reconnected to the LoRa module");
+                        }
+                    }
+                }
+                Err(err) => eprintln!("Error reading from port: {}", err),
+            },
+            Err(poisoned) => {
+                eprintln!("Mutex was poisoned. Inner error: {:?}", poisoned);
+            }
+        }
+
+        thread::sleep(Duration::from_millis(25));
+    })
+}
+
+/// Work through `node`'s outbox and send its beacons on a background thread, so
+/// retries go on without the UI.
+pub fn spawn_outbox(node: Arc<Mutex<Node>>) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        match node.lock() {
+            Ok(mut node) => {
+                node.flush_outbox();
+                node.beacon_if_due();
+            }
+            Err(poisoned) => {
+                eprintln!("Mutex was poisoned. Inner error: {:?}", poisoned);
+            }
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    })
+}
+
+/// Seconds since the UNIX epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,142 @@
+use crate::protocol::MessageId;
+use std::time::{Duration, Instant};
+
+/// When to resend an unconfirmed message and when to give up on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Wait after the first transmission; doubled after each further one.
+    pub first_delay: Duration,
+    /// Transmissions, the first included, before the message is marked failed.
+    pub max_attempts: u64,
+    /// Wait before trying again when the radio refused the command.
+    pub write_error_delay: Duration,
+    /// Least wait between two frames from the outbox. The module is busy while one is
+    /// on the air, and the board's serial buffer holds little more than one command.
+    pub frame_gap: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            first_delay: Duration::from_secs(10),
+            max_attempts: 4,
+            write_error_delay: Duration::from_secs(1),
+            frame_gap: Duration::from_millis(1500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait for a confirmation after transmission number `attempts`.
+    pub fn delay(&self, attempts: u64) -> Duration {
+        let doublings = attempts.saturating_sub(1).min(16) as u32;
+        self.first_delay * 2u32.pow(doublings)
+    }
+}
+
+/// One of our messages still waiting for a confirmation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pending {
+    /// The conversation the message is in
+    pub peer: String,
+    pub id: MessageId,
+    /// Transmissions so far
+    pub attempts: u64,
+    pub due: Instant,
+    /// Fragments the recipient said it has since a retry last sent them all, one bit
+    /// each, if the message was split
+    pub acked: u64,
+    /// Most fragments the recipient has said it had at once
+    pub most_acked: u32,
+    /// Fragments of the transmission under way still to go out, one bit each
+    pub unsent: u64,
+}
+
+/// Outgoing messages waiting for their next transmission, worked through by
+/// [`Node::flush_outbox`](crate::node::Node::flush_outbox).
+#[derive(Debug, Default)]
+pub struct Outbox {
+    pending: Vec<Pending>,
+    policy: RetryPolicy,
+    /// When the last frame went out
+    last_sent: Option<Instant>,
+}
+
+impl Outbox {
+    pub fn policy(&self) -> RetryPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+
+    /// Queue a message for sending as soon as possible.
+    pub fn push(&mut self, peer: &str, id: MessageId, attempts: u64) {
+        self.pending
+            .retain(|pending| !(pending.peer == peer && pending.id == id));
+        self.pending.push(Pending {
+            peer: peer.to_string(),
+            id,
+            attempts,
+            due: Instant::now(),
+            acked: 0,
+            most_acked: 0,
+            unsent: 0,
+        });
+    }
+
+    /// Note that `peer` has `received` fragments of message `id`, and send the rest
+    /// right away if it has more than it ever had. The peer is evidently getting
+    /// through, so retries start over. Returns the fragments known to have arrived, or
+    /// `None` if the message isn't waiting.
+    pub fn acknowledge(&mut self, peer: &str, id: MessageId, received: u64) -> Option<u64> {
+        let pending = self
+            .pending
+            .iter_mut()
+            .find(|pending| pending.peer == peer && pending.id == id)?;
+        pending.acked |= received;
+        pending.unsent &= !received;
+        // After a retry sent them all again it acks what it had before, which isn't
+        // progress, or retries would never run out
+        let count = pending.acked.count_ones();
+        if count > pending.most_acked {
+            pending.most_acked = count;
+            pending.attempts = 0;
+            pending.due = Instant::now();
+        }
+        Some(pending.acked)
+    }
+
+    /// Whether the frame gap since the last transmission is over by `now`.
+    pub fn ready(&self, now: Instant) -> bool {
+        self.last_sent.map_or(true, |last| {
+            now.saturating_duration_since(last) >= self.policy.frame_gap
+        })
+    }
+
+    /// Note that a frame went out at `now`.
+    pub fn sent(&mut self, now: Instant) {
+        self.last_sent = Some(now);
+    }
+
+    /// Take out the entry to transmit for next, if any is due by `now`: one with
+    /// fragments still to go first, so a split message goes out in one piece, then the
+    /// one due longest.
+    pub fn take_next(&mut self, now: Instant) -> Option<Pending> {
+        let index = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, pending)| pending.due <= now)
+            .min_by_key(|(_, pending)| (pending.unsent == 0, pending.due))
+            .map(|(index, _)| index)?;
+        Some(self.pending.remove(index))
+    }
+
+    /// Put an entry back to be handled at `due`.
+    pub fn schedule(&mut self, mut pending: Pending, due: Instant) {
+        pending.due = due;
+        self.pending.push(pending);
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// How often to send beacons and how long a station counts as present after one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct PresencePolicy {
+    /// Seconds between our beacons; 0 sends none.
+    pub interval_secs: u64,
+    /// How many relays may pass our beacons on.
+    pub ttl: u8,
+    /// A station not heard from for longer than this is idle.
+    pub idle_after_secs: u64,
+    /// A station not heard from for longer than this is offline.
+    pub offline_after_secs: u64,
+}
+
+impl Default for PresencePolicy {
+    fn default() -> Self {
+        Self {
+            interval_secs: 60,
+            ttl: 2,
+            idle_after_secs: 180,
+            offline_after_secs: 600,
+        }
+    }
+}
+
+/// Whether a station has been heard from lately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Online,
+    Idle,
+    Offline,
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Presence::Online => write!(f, "online"),
+            Presence::Idle => write!(f, "idle"),
+            Presence::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Heard {
+    /// UNIX time we heard the station
+    at: u64,
+    /// Send time of its newest signed frame
+    sent: u64,
+}
+
+/// When each station was last heard from, by UID.
+///
+/// Only signed frames count, beacons and announcements, so nobody can make another
+/// station look present. Each must be newer than the last one from its sender, and
+/// recent enough to say something about now, so a recorded one played back is ignored.
+#[derive(Debug, Clone, Default)]
+pub struct PresenceTable {
+    heard: HashMap<String, Heard>,
+    policy: PresencePolicy,
+}
+
+/// The presence table shared between the node and the UI.
+pub type SharedPresence = Arc<Mutex<PresenceTable>>;
+
+impl PresenceTable {
+    pub fn policy(&self) -> PresencePolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: PresencePolicy) {
+        self.policy = policy;
+    }
+
+    /// UNIX time `uid` was last heard from, if ever.
+    pub fn last_heard(&self, uid: &str) -> Option<u64> {
+        self.heard.get(uid).map(|heard| heard.at)
+    }
+
+    /// Whether `uid` counts as present at `now`.
+    pub fn status(&self, uid: &str, now: u64) -> Presence {
+        let Some(at) = self.last_heard(uid) else {
+            return Presence::Offline;
+        };
+        let silent = now.saturating_sub(at);
+        if silent <= self.policy.idle_after_secs {
+            Presence::Online
+        } else if silent <= self.policy.offline_after_secs {
+            Presence::Idle
+        } else {
+            Presence::Offline
+        }
+    }
+
+    /// Note a signed frame `uid` sent at `sent`, heard at `now`. Returns false if it is
+    /// no newer than the last one, or too old to tell whether `uid` is still there.
+    pub fn heard(&mut self, uid: &str, sent: u64, now: u64) -> bool {
+        if sent + self.policy.offline_after_secs < now {
+            return false;
+        }
+        if let Some(heard) = self.heard.get(uid) {
+            if sent <= heard.sent {
+                return false;
+            }
+        }
+        self.heard.insert(uid.to_string(), Heard { at: now, sent });
+        true
+    }
+}
@@ -0,0 +1,1020 @@
+use std::fmt;
+
+/// Length of a module UID as returned by `AT+UID?`.
+pub const UID_LEN: usize = 24;
+/// Length of the UNIX timestamp field (seconds, zero padded).
+pub const TIME_LEN: usize = 10;
+/// Length of a message ID field (hex).
+pub const ID_LEN: usize = 8;
+
+/// Most relays a frame can be allowed, as the TTL is a single hex digit.
+pub const MAX_TTL: u8 = 15;
+/// How many relays may pass a frame on unless its sender says otherwise.
+pub const DEFAULT_TTL: u8 = 7;
+
+/// Largest data field the RYLR module accepts in one `AT+SEND`.
+pub const MAX_PAYLOAD: usize = 240;
+/// Most fragments a message can be split into, one bit each in a fragment ack.
+pub const MAX_FRAGMENTS: usize = 64;
+
+/// Length of the base64 public keys in an announcement (two 32 byte keys).
+pub const PUBLIC_KEY_LEN: usize = 86;
+/// Length of a base64 Ed25519 signature.
+pub const SIGNATURE_LEN: usize = 86;
+/// Length of a base64 confirmation MAC (16 bytes).
+pub const TAG_LEN: usize = 22;
+
+/// The recipient of frames meant for every station.
+pub const BROADCAST: &str = "FFFFFFFFFFFFFFFFFFFFFFFF";
+
+const MESSAGE_TAG: &str = "MSG";
+const ACK_TAG: &str = "ACK";
+const CONFIRMED_TAG: &str = "CONFIRMED";
+const ANNOUNCE_TAG: &str = "ANNOUNCE";
+const ROUTE_REQUEST_TAG: &str = "RREQ";
+const ROUTE_REPLY_TAG: &str = "RREP";
+const BEACON_TAG: &str = "BEACON";
+const FRAGMENT_TAG: &str = "FRAG";
+const FRAGMENT_ACK_TAG: &str = "FACK";
+/// Starts a frame sent to one relay rather than flooded
+const NEXT_HOP_TAG: &str = ">";
+
+/// Picked at random by the sender of a message. Together with the sender's UID it
+/// names the message across retries, relays and its confirmation.
+pub type MessageId = u32;
+
+/// A packet carried in the data field of `AT+SEND` / `+RCV=`.
+///
+/// Layouts on the wire:
+/// * `Data`:         `MSG ttl(1) hops(1) id(8) recipient(24) sender(24) time(10) body`
+/// * `Confirmation`: `ACK ttl(1) hops(1) id(8) time(10) recipient(24) sender(24) [tag(22)]`
+/// * `Announce`:     `ANNOUNCE ttl(1) hops(1) sender(24) time(10) public_key(86) signature(86)`
+/// * `RouteRequest`: `RREQ ttl(1) hops(1) id(8) time(10) recipient(24) sender(24) last_hop(24)`
+/// * `RouteReply`:   `RREP ttl(1) hops(1) id(8) time(10) recipient(24) sender(24) next(24) last_hop(24)`
+/// * `Beacon`:       `BEACON ttl(1) hops(1) time(10) sender(24) signature(86)`
+/// * `Fragment`:     `FRAG ttl(1) hops(1) id(8) index(2) count(2) recipient(24) sender(24) time(10) chunk`
+/// * `FragmentAck`:  `FACK ttl(1) hops(1) id(8) received(16) time(10) recipient(24) sender(24) tag(22)?`
+///
+/// `ttl` and `hops` are hex digits: how many more relays may pass the frame on, and
+/// how many already have. Each relay moves one from the first to the second, so
+/// neither is covered by the seal or the confirmation tag.
+///
+/// Data, confirmations, fragments and their acks with a `next` hop are prefixed with
+/// `> next(24)`. Only that station passes them on, and it picks the next one; without
+/// it every station does.
+///
+/// Older clients send data and confirmations without an ID or hop counts, as
+/// `recipient(24) sender(24) time(10) body` and
+/// `CONFIRMED time(10) recipient(24) sender(24) [tag(22)]`. Those decode with
+/// `id: None` and both counts zero, and a frame with `id: None` encodes back to the
+/// old layout.
+///
+/// The body is percent-escaped on the wire (see [`escape_body`]) so the payload is
+/// always plain ASCII without commas or line breaks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// A chat message from `sender` to `recipient`.
+    Data {
+        id: Option<MessageId>,
+        ttl: u8,
+        hops: u8,
+        next: Option<String>,
+        recipient: String,
+        sender: String,
+        time: u64,
+        body: String,
+    },
+    /// Sent by `sender` to tell `recipient` that its message `id`, sent at `time`, arrived.
+    ///
+    /// `tag` is a MAC over the encoding of the frame without it, keyed with the pair
+    /// key, so only the real recipient of the message can confirm it. Older clients
+    /// send no tag.
+    Confirmation {
+        id: Option<MessageId>,
+        ttl: u8,
+        hops: u8,
+        next: Option<String>,
+        time: u64,
+        recipient: String,
+        sender: String,
+        tag: Option<String>,
+    },
+    /// Broadcast by `sender` to bind its public keys to its UID.
+    ///
+    /// The signature covers the encoding of the frame as sent with an empty
+    /// `signature`, so relays can count hops on it without breaking it.
+    Announce {
+        ttl: u8,
+        hops: u8,
+        sender: String,
+        time: u64,
+        public_key: String,
+        signature: String,
+    },
+    /// Flooded by `sender` to find a route to `recipient`. Every station passing it on
+    /// puts its own UID in `last_hop`, so the ones it reaches learn the way back.
+    RouteRequest {
+        id: MessageId,
+        ttl: u8,
+        hops: u8,
+        time: u64,
+        recipient: String,
+        sender: String,
+        last_hop: String,
+    },
+    /// The answer from `sender`, the station a route was requested to, to request `id`
+    /// of `recipient`. It travels back hop by hop, each `next` passing it on.
+    RouteReply {
+        id: MessageId,
+        ttl: u8,
+        hops: u8,
+        time: u64,
+        recipient: String,
+        sender: String,
+        next: String,
+        last_hop: String,
+    },
+    /// Broadcast by `sender` every so often to say it is on the air.
+    ///
+    /// The signature covers the encoding of the frame as sent with an empty
+    /// `signature`, so relays can count hops on it without breaking it.
+    Beacon {
+        ttl: u8,
+        hops: u8,
+        time: u64,
+        sender: String,
+        signature: String,
+    },
+    /// Part `index` of `count` of a data frame too long for one packet. `chunk` is a
+    /// piece of the escaped body, cut between escapes, so the pieces join back into it.
+    Fragment {
+        id: MessageId,
+        ttl: u8,
+        hops: u8,
+        next: Option<String>,
+        index: u8,
+        count: u8,
+        recipient: String,
+        sender: String,
+        time: u64,
+        chunk: String,
+    },
+    /// Sent by `sender` to tell `recipient` which fragments of its message `id`, sent
+    /// at `time`, arrived so far: bit `i` of `received` for fragment `i`. Once all are
+    /// there a confirmation is sent instead.
+    ///
+    /// Like a confirmation's, the tag is a MAC over the encoding of the frame as sent
+    /// with no tag.
+    FragmentAck {
+        id: MessageId,
+        ttl: u8,
+        hops: u8,
+        next: Option<String>,
+        time: u64,
+        recipient: String,
+        sender: String,
+        received: u64,
+        tag: Option<String>,
+    },
+}
+
+impl Frame {
+    pub fn recipient(&self) -> &str {
+        match self {
+            Frame::Data { recipient, .. }
+            | Frame::Confirmation { recipient, .. }
+            | Frame::RouteRequest { recipient, .. }
+            | Frame::RouteReply { recipient, .. }
+            | Frame::Fragment { recipient, .. }
+            | Frame::FragmentAck { recipient, .. } => recipient,
+            Frame::Announce { .. } | Frame::Beacon { .. } => BROADCAST,
+        }
+    }
+
+    pub fn sender(&self) -> &str {
+        match self {
+            Frame::Data { sender, .. }
+            | Frame::Confirmation { sender, .. }
+            | Frame::Announce { sender, .. }
+            | Frame::RouteRequest { sender, .. }
+            | Frame::RouteReply { sender, .. }
+            | Frame::Beacon { sender, .. }
+            | Frame::Fragment { sender, .. }
+            | Frame::FragmentAck { sender, .. } => sender,
+        }
+    }
+
+    /// The message a data frame or fragment carries or a confirmation is for, if the
+    /// sender gave one, or the route request a route frame belongs to.
+    pub fn id(&self) -> Option<MessageId> {
+        match self {
+            Frame::Data { id, .. } | Frame::Confirmation { id, .. } => *id,
+            Frame::RouteRequest { id, .. }
+            | Frame::RouteReply { id, .. }
+            | Frame::Fragment { id, .. }
+            | Frame::FragmentAck { id, .. } => Some(*id),
+            Frame::Announce { .. } | Frame::Beacon { .. } => None,
+        }
+    }
+
+    /// How many relays have passed the frame on.
+    pub fn hops(&self) -> u8 {
+        match self {
+            Frame::Data { hops, .. }
+            | Frame::Confirmation { hops, .. }
+            | Frame::RouteRequest { hops, .. }
+            | Frame::RouteReply { hops, .. }
+            | Frame::Announce { hops, .. }
+            | Frame::Beacon { hops, .. }
+            | Frame::Fragment { hops, .. }
+            | Frame::FragmentAck { hops, .. } => *hops,
+        }
+    }
+
+    /// The station that should pass the frame on, if it isn't meant for every one.
+    pub fn next(&self) -> Option<&str> {
+        match self {
+            Frame::Data { next, .. }
+            | Frame::Confirmation { next, .. }
+            | Frame::Fragment { next, .. }
+            | Frame::FragmentAck { next, .. } => next.as_deref(),
+            Frame::RouteReply { next, .. } => Some(next),
+            Frame::Announce { .. } | Frame::RouteRequest { .. } | Frame::Beacon { .. } => None,
+        }
+    }
+
+    /// The station this copy of the frame was heard from, when the frame tells.
+    pub fn transmitter(&self) -> Option<&str> {
+        match self {
+            Frame::RouteRequest { last_hop, .. } | Frame::RouteReply { last_hop, .. } => {
+                Some(last_hop)
+            }
+            // Relays count a hop, so zero means the sender transmitted it itself
+            Frame::Data { id: Some(_), .. }
+            | Frame::Confirmation { id: Some(_), .. }
+            | Frame::Announce { .. }
+            | Frame::Beacon { .. }
+            | Frame::Fragment { .. }
+            | Frame::FragmentAck { .. }
+                if self.hops() == 0 =>
+            {
+                Some(self.sender())
+            }
+            _ => None,
+        }
+    }
+
+    /// The frame as its sender encoded it, before any relay counted a hop on it or
+    /// picked the next one.
+    pub fn as_sent(&self) -> Frame {
+        let mut frame = self.clone();
+        match &mut frame {
+            Frame::Data {
+                ttl, hops, next, ..
+            }
+            | Frame::Confirmation {
+                ttl, hops, next, ..
+            }
+            | Frame::Fragment {
+                ttl, hops, next, ..
+            }
+            | Frame::FragmentAck {
+                ttl, hops, next, ..
+            } => {
+                *ttl = ttl.saturating_add(*hops);
+                *hops = 0;
+                *next = None;
+            }
+            Frame::RouteRequest {
+                ttl,
+                hops,
+                sender,
+                last_hop,
+                ..
+            } => {
+                *ttl = ttl.saturating_add(*hops);
+                *hops = 0;
+                *last_hop = sender.clone();
+            }
+            Frame::Announce { ttl, hops, .. } | Frame::Beacon { ttl, hops, .. } => {
+                *ttl = ttl.saturating_add(*hops);
+                *hops = 0;
+            }
+            Frame::RouteReply { .. } => {}
+        }
+        frame
+    }
+
+    /// The frame as relay `via` passes it on to `next` (or to everyone), or `None` once
+    /// it has used up its hop limit. Frames in the old layout carry no limit and are
+    /// passed on as they are.
+    pub fn relayed(&self, via: &str, next: Option<String>) -> Option<Frame> {
+        let mut frame = self.clone();
+        match &mut frame {
+            Frame::Data {
+                id: Some(_),
+                ttl,
+                hops,
+                next: field,
+                ..
+            }
+            | Frame::Confirmation {
+                id: Some(_),
+                ttl,
+                hops,
+                next: field,
+                ..
+            }
+            | Frame::Fragment {
+                ttl,
+                hops,
+                next: field,
+                ..
+            }
+            | Frame::FragmentAck {
+                ttl,
+                hops,
+                next: field,
+                ..
+            } => {
+                *ttl = ttl.checked_sub(1)?;
+                *hops = hops.saturating_add(1).min(MAX_TTL);
+                *field = next;
+            }
+            Frame::RouteRequest {
+                ttl,
+                hops,
+                last_hop,
+                ..
+            } => {
+                *ttl = ttl.checked_sub(1)?;
+                *hops = hops.saturating_add(1).min(MAX_TTL);
+                *last_hop = via.to_string();
+            }
+            Frame::RouteReply {
+                ttl,
+                hops,
+                next: field,
+                last_hop,
+                ..
+            } => {
+                *ttl = ttl.checked_sub(1)?;
+                *hops = hops.saturating_add(1).min(MAX_TTL);
+                *field = next?;
+                *last_hop = via.to_string();
+            }
+            Frame::Announce { ttl, hops, .. } | Frame::Beacon { ttl, hops, .. } => {
+                *ttl = ttl.checked_sub(1)?;
+                *hops = hops.saturating_add(1).min(MAX_TTL);
+            }
+            Frame::Data { id: None, .. } | Frame::Confirmation { id: None, .. } => {}
+        }
+        Some(frame)
+    }
+
+    pub fn time(&self) -> u64 {
+        match self {
+            Frame::Data { time, .. }
+            | Frame::Confirmation { time, .. }
+            | Frame::Announce { time, .. }
+            | Frame::RouteRequest { time, .. }
+            | Frame::RouteReply { time, .. }
+            | Frame::Beacon { time, .. }
+            | Frame::Fragment { time, .. }
+            | Frame::FragmentAck { time, .. } => *time,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended before a fixed-size field was complete.
+    TooShort { expected: usize, found: usize },
+    /// Something followed the last field of a frame that has no body.
+    TooLong { expected: usize, found: usize },
+    /// A field did not fall on a character boundary or had the wrong shape.
+    InvalidField(&'static str),
+    /// The line is not a `+RCV=` notification.
+    NotReceive,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort { expected, found } => {
+                write!(
+                    f,
+                    "frame too short: expected {expected} bytes, found {found}"
+                )
+            }
+            DecodeError::TooLong { expected, found } => {
+                write!(
+                    f,
+                    "frame too long: expected {expected} bytes, found {found}"
+                )
+            }
+            DecodeError::InvalidField(name) => write!(f, "invalid {name} field"),
+            DecodeError::NotReceive => write!(f, "not a +RCV line"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The payload is longer than the module can send in one packet.
+    TooLong { length: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::TooLong { length } => {
+                write!(f, "payload of {length} bytes exceeds {MAX_PAYLOAD}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Serialize a frame into the payload passed to `AT+SEND`.
+pub fn encode(frame: &Frame) -> String {
+    match frame {
+        Frame::Data {
+            id,
+            ttl,
+            hops,
+            next,
+            recipient,
+            sender,
+            time,
+            body,
+        } => {
+            let body = escape_body(body);
+            match id {
+                Some(id) => format!(
+                    "{}{MESSAGE_TAG}{}{id:0ID_LEN$X}{recipient}{sender}{time:0TIME_LEN$}{body}",
+                    next_hop(next),
+                    hop_counts(*ttl, *hops)
+                ),
+                None => format!("{recipient}{sender}{time:0TIME_LEN$}{body}"),
+            }
+        }
+        Frame::Confirmation {
+            id,
+            ttl,
+            hops,
+            next,
+            time,
+            recipient,
+            sender,
+            tag,
+        } => {
+            let tag = tag.as_deref().unwrap_or_default();
+            match id {
+                Some(id) => format!(
+                    "{}{ACK_TAG}{}{id:0ID_LEN$X}{time:0TIME_LEN$}{recipient}{sender}{tag}",
+                    next_hop(next),
+                    hop_counts(*ttl, *hops)
+                ),
+                None => format!("{CONFIRMED_TAG}{time:0TIME_LEN$}{recipient}{sender}{tag}"),
+            }
+        }
+        Frame::RouteRequest {
+            id,
+            ttl,
+            hops,
+            time,
+            recipient,
+            sender,
+            last_hop,
+        } => format!(
+            "{ROUTE_REQUEST_TAG}{}{id:0ID_LEN$X}{time:0TIME_LEN$}{recipient}{sender}{last_hop}",
+            hop_counts(*ttl, *hops)
+        ),
+        Frame::RouteReply {
+            id,
+            ttl,
+            hops,
+            time,
+            recipient,
+            sender,
+            next,
+            last_hop,
+        } => format!(
+            "{ROUTE_REPLY_TAG}{}{id:0ID_LEN$X}{time:0TIME_LEN$}{recipient}{sender}{next}{last_hop}",
+            hop_counts(*ttl, *hops)
+        ),
+        Frame::Fragment {
+            id,
+            ttl,
+            hops,
+            next,
+            index,
+            count,
+            recipient,
+            sender,
+            time,
+            chunk,
+        } => format!(
+            "{}{FRAGMENT_TAG}{}{id:0ID_LEN$X}{index:02X}{count:02X}{recipient}{sender}{time:0TIME_LEN$}{chunk}",
+            next_hop(next),
+            hop_counts(*ttl, *hops)
+        ),
+        Frame::FragmentAck {
+            id,
+            ttl,
+            hops,
+            next,
+            time,
+            recipient,
+            sender,
+            received,
+            tag,
+        } => format!(
+            "{}{FRAGMENT_ACK_TAG}{}{id:0ID_LEN$X}{received:016X}{time:0TIME_LEN$}{recipient}{sender}{}",
+            next_hop(next),
+            hop_counts(*ttl, *hops),
+            tag.as_deref().unwrap_or_default()
+        ),
+        Frame::Announce {
+            ttl,
+            hops,
+            sender,
+            time,
+            public_key,
+            signature,
+        } => format!(
+            "{ANNOUNCE_TAG}{}{sender}{time:0TIME_LEN$}{public_key}{signature}",
+            hop_counts(*ttl, *hops)
+        ),
+        Frame::Beacon {
+            ttl,
+            hops,
+            time,
+            sender,
+            signature,
+        } => format!(
+            "{BEACON_TAG}{}{time:0TIME_LEN$}{sender}{signature}",
+            hop_counts(*ttl, *hops)
+        ),
+    }
+}
+
+/// Parse a payload produced by [`encode`].
+pub fn decode(payload: &str) -> Result<Frame, DecodeError> {
+    let mut fields = Fields::new(payload);
+    let next = match payload.strip_prefix(NEXT_HOP_TAG) {
+        Some(_) => {
+            fields.take(NEXT_HOP_TAG.len(), "tag")?;
+            Some(fields.uid("next")?)
+        }
+        None => None,
+    };
+    let payload = fields.rest();
+    let mut fields = Fields::new(payload);
+    if payload.starts_with(ROUTE_REQUEST_TAG) || payload.starts_with(ROUTE_REPLY_TAG) {
+        let request = payload.starts_with(ROUTE_REQUEST_TAG);
+        fields.take(ROUTE_REQUEST_TAG.len(), "tag")?;
+        let (ttl, hops) = (fields.digit("ttl")?, fields.digit("hops")?);
+        let id = fields.id()?;
+        let time = fields.time()?;
+        let recipient = fields.uid("recipient")?;
+        let sender = fields.uid("sender")?;
+        if request {
+            let last_hop = fields.uid("last hop")?;
+            fields.end()?;
+            Ok(Frame::RouteRequest {
+                id,
+                ttl,
+                hops,
+                time,
+                recipient,
+                sender,
+                last_hop,
+            })
+        } else {
+            let next = fields.uid("next")?;
+            let last_hop = fields.uid("last hop")?;
+            fields.end()?;
+            Ok(Frame::RouteReply {
+                id,
+                ttl,
+                hops,
+                time,
+                recipient,
+                sender,
+                next,
+                last_hop,
+            })
+        }
+    } else if payload.starts_with(CONFIRMED_TAG) || payload.starts_with(ACK_TAG) {
+        let (id, ttl, hops) = if payload.starts_with(ACK_TAG) {
+            fields.take(ACK_TAG.len(), "tag")?;
+            let (ttl, hops) = (fields.digit("ttl")?, fields.digit("hops")?);
+            (Some(fields.id()?), ttl, hops)
+        } else {
+            fields.take(CONFIRMED_TAG.len(), "tag")?;
+            (None, 0, 0)
+        };
+        let time = fields.time()?;
+        let recipient = fields.uid("recipient")?;
+        let sender = fields.uid("sender")?;
+        let tag = fields.tag()?;
+        Ok(Frame::Confirmation {
+            id,
+            ttl,
+            hops,
+            next: id.and(next),
+            time,
+            recipient,
+            sender,
+            tag,
+        })
+    } else if payload.starts_with(FRAGMENT_TAG) {
+        fields.take(FRAGMENT_TAG.len(), "tag")?;
+        let (ttl, hops) = (fields.digit("ttl")?, fields.digit("hops")?);
+        let id = fields.id()?;
+        let index = fields.hex(2, "index")? as u8;
+        let count = fields.hex(2, "count")? as u8;
+        if index >= count || usize::from(count) > MAX_FRAGMENTS {
+            return Err(DecodeError::InvalidField("count"));
+        }
+        let recipient = fields.uid("recipient")?;
+        let sender = fields.uid("sender")?;
+        let time = fields.time()?;
+        Ok(Frame::Fragment {
+            id,
+            ttl,
+            hops,
+            next,
+            index,
+            count,
+            recipient,
+            sender,
+            time,
+            chunk: fields.rest().to_string(),
+        })
+    } else if payload.starts_with(FRAGMENT_ACK_TAG) {
+        fields.take(FRAGMENT_ACK_TAG.len(), "tag")?;
+        let (ttl, hops) = (fields.digit("ttl")?, fields.digit("hops")?);
+        let id = fields.id()?;
+        let received = fields.hex(16, "received")?;
+        let time = fields.time()?;
+        let recipient = fields.uid("recipient")?;
+        let sender = fields.uid("sender")?;
+        let tag = fields.tag()?;
+        Ok(Frame::FragmentAck {
+            id,
+            ttl,
+            hops,
+            next,
+            time,
+            recipient,
+            sender,
+            received,
+            tag,
+        })
+    } else if payload.starts_with(BEACON_TAG) {
+        fields.take(BEACON_TAG.len(), "tag")?;
+        let (ttl, hops) = (fields.digit("ttl")?, fields.digit("hops")?);
+        let time = fields.time()?;
+        let sender = fields.uid("sender")?;
+        let signature = fields.take(SIGNATURE_LEN, "signature")?.to_string();
+        fields.end()?;
+        Ok(Frame::Beacon {
+            ttl,
+            hops,
+            time,
+            sender,
+            signature,
+        })
+    } else if payload.starts_with(ANNOUNCE_TAG) {
+        fields.take(ANNOUNCE_TAG.len(), "tag")?;
+        let (ttl, hops) = (fields.digit("ttl")?, fields.digit("hops")?);
+        let sender = fields.uid("sender")?;
+        let time = fields.time()?;
+        let public_key = fields.take(PUBLIC_KEY_LEN, "public key")?.to_string();
+        let signature = fields.take(SIGNATURE_LEN, "signature")?.to_string();
+        fields.end()?;
+        Ok(Frame::Announce {
+            ttl,
+            hops,
+            sender,
+            time,
+            public_key,
+            signature,
+        })
+    } else {
+        // UIDs are hex, so a legacy frame can't start with the tag
+        let (id, ttl, hops) = if payload.starts_with(MESSAGE_TAG) {
+            fields.take(MESSAGE_TAG.len(), "tag")?;
+            let (ttl, hops) = (fields.digit("ttl")?, fields.digit("hops")?);
+            (Some(fields.id()?), ttl, hops)
+        } else {
+            (None, 0, 0)
+        };
+        let recipient = fields.uid("recipient")?;
+        let sender = fields.uid("sender")?;
+        let time = fields.time()?;
+        Ok(Frame::Data {
+            id,
+            ttl,
+            hops,
+            next: id.and(next),
+            recipient,
+            sender,
+            time,
+            body: unescape_body(fields.rest()),
+        })
+    }
+}
+
+/// Whether `uid` has the shape of a module UID: 24 hex digits.
+pub fn is_uid(uid: &str) -> bool {
+    uid.len() == UID_LEN && uid.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// The fields of a data frame that its seal covers: the ones before the body, except
+/// the hop counts relays change.
+pub fn data_header(id: Option<MessageId>, recipient: &str, sender: &str, time: u64) -> String {
+    match id {
+        Some(id) => format!("{MESSAGE_TAG}{id:0ID_LEN$X}{recipient}{sender}{time:0TIME_LEN$}"),
+        None => format!("{recipient}{sender}{time:0TIME_LEN$}"),
+    }
+}
+
+/// The prefix naming the next hop, if there is one.
+fn next_hop(next: &Option<String>) -> String {
+    match next {
+        Some(next) => format!("{NEXT_HOP_TAG}{next}"),
+        None => String::new(),
+    }
+}
+
+/// The `ttl` and `hops` fields, clamped to a digit each.
+fn hop_counts(ttl: u8, hops: u8) -> String {
+    format!("{:X}{:X}", ttl.min(MAX_TTL), hops.min(MAX_TTL))
+}
+
+/// Split a data frame too long for one packet into fragments that each fit, even
+/// with a next hop added. Returns `None` if it would take more than [`MAX_FRAGMENTS`],
+/// and the frame alone if it fits as it is.
+pub fn fragment(frame: &Frame) -> Option<Vec<Frame>> {
+    let Frame::Data {
+        id: Some(id),
+        ttl,
+        recipient,
+        sender,
+        time,
+        body,
+        ..
+    } = frame
+    else {
+        return Some(vec![frame.clone()]);
+    };
+    let mut unrouted = frame.as_sent();
+    if let Frame::Data { next, .. } = &mut unrouted {
+        *next = Some(sender.clone());
+    }
+    if encode(&unrouted).len() <= MAX_PAYLOAD {
+        return Some(vec![frame.clone()]);
+    }
+    let piece = |index, count, chunk: &str| Frame::Fragment {
+        id: *id,
+        ttl: *ttl,
+        hops: 0,
+        next: None,
+        index,
+        count,
+        recipient: recipient.clone(),
+        sender: sender.clone(),
+        time: *time,
+        chunk: chunk.to_string(),
+    };
+    let mut header = piece(0, 0, "");
+    if let Frame::Fragment { next, .. } = &mut header {
+        *next = Some(sender.clone());
+    }
+    let room = MAX_PAYLOAD - encode(&header).len();
+    let escaped = escape_body(body);
+    let mut chunks = Vec::new();
+    let mut rest = escaped.as_str();
+    while !rest.is_empty() {
+        let mut end = room.min(rest.len());
+        // Don't cut an escape in two
+        if let Some(percent) = rest[end.saturating_sub(2)..end].find('%') {
+            if end < rest.len() {
+                end = end.saturating_sub(2) + percent;
+            }
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    if chunks.len() > MAX_FRAGMENTS {
+        return None;
+    }
+    let count = chunks.len() as u8;
+    Some(
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| piece(index as u8, count, chunk))
+            .collect(),
+    )
+}
+
+/// Percent-escape a message body for the radio.
+///
+/// The `+RCV=` line is comma separated and terminated by CR/LF, and the module only
+/// handles ASCII reliably, so every byte outside printable ASCII is written as `%XX`,
+/// as are `,` and `%` themselves. The result's `len()` is its byte count on the air.
+pub fn escape_body(body: &str) -> String {
+    let mut escaped = String::with_capacity(body.len());
+    for byte in body.bytes() {
+        if (0x20..0x7f).contains(&byte) && byte != b',' && byte != b'%' {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    escaped
+}
+
+/// Reverse [`escape_body`].
+///
+/// Malformed escapes are kept literally so bodies from older clients, which sent
+/// text unescaped, still come through.
+pub fn unescape_body(escaped: &str) -> String {
+    let input = escaped.as_bytes();
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let decoded = match input.get(i + 1..i + 3) {
+            Some(hex) if input[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit) => {
+                std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            }
+            _ => None,
+        };
+        match decoded {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            None => {
+                bytes.push(input[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The `AT+SEND` command that broadcasts `payload` (address 0 reaches every module).
+///
+/// Every transmission goes through here: first sends, retries and relays alike. The
+/// length field is the payload's byte count, which is what the module checks it against.
+pub fn send_command(payload: &str) -> Result<String, EncodeError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(EncodeError::TooLong {
+            length: payload.len(),
+        });
+    }
+    Ok(format!("AT+SEND=0,{},{}", payload.len(), payload))
+}
+
+/// A `+RCV=<address>,<length>,<data>,<rssi>,<snr>` notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reception<'a> {
+    /// `AT+ADDRESS` of the module that transmitted
+    pub address: u16,
+    pub payload: &'a str,
+    /// Signal strength in dBm
+    pub rssi: i32,
+    /// Signal to noise ratio in dB
+    pub snr: i32,
+}
+
+/// Parse a `+RCV=` line. The data field is cut by its length, as it may hold commas.
+pub fn receive(line: &str) -> Result<Reception<'_>, DecodeError> {
+    let start = line.find("+RCV=").ok_or(DecodeError::NotReceive)?;
+    let mut parts = line[start + "+RCV=".len()..].splitn(3, ',');
+    let address = parts
+        .next()
+        .and_then(|address| address.trim().parse().ok())
+        .ok_or(DecodeError::InvalidField("address"))?;
+    let length: usize = parts
+        .next()
+        .and_then(|len| len.trim().parse().ok())
+        .ok_or(DecodeError::InvalidField("length"))?;
+    let rest = parts.next().unwrap_or_default();
+    let payload = rest.get(..length).ok_or(DecodeError::TooShort {
+        expected: length,
+        found: rest.len(),
+    })?;
+    let mut signal = rest[length..].trim_end().trim_start_matches(',').split(',');
+    let mut signal_field = |name| {
+        signal
+            .next()
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or(DecodeError::InvalidField(name))
+    };
+    let rssi = signal_field("rssi")?;
+    let snr = signal_field("snr")?;
+    Ok(Reception {
+        address,
+        payload,
+        rssi,
+        snr,
+    })
+}
+
+/// Cursor over the fixed-width fields of a payload.
+struct Fields<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(input: &'a str) -> Self {
+        Self { input, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize, name: &'static str) -> Result<&'a str, DecodeError> {
+        let end = self.pos + len;
+        if end > self.input.len() {
+            return Err(DecodeError::TooShort {
+                expected: end,
+                found: self.input.len(),
+            });
+        }
+        let field = self
+            .input
+            .get(self.pos..end)
+            .ok_or(DecodeError::InvalidField(name))?;
+        self.pos = end;
+        Ok(field)
+    }
+
+    fn uid(&mut self, name: &'static str) -> Result<String, DecodeError> {
+        let uid = self.take(UID_LEN, name)?;
+        if !is_uid(uid) {
+            return Err(DecodeError::InvalidField(name));
+        }
+        Ok(uid.to_string())
+    }
+
+    fn digit(&mut self, name: &'static str) -> Result<u8, DecodeError> {
+        let digit = self.take(1, name)?;
+        u8::from_str_radix(digit, 16).map_err(|_| DecodeError::InvalidField(name))
+    }
+
+    fn id(&mut self) -> Result<MessageId, DecodeError> {
+        Ok(self.hex(ID_LEN, "id")? as MessageId)
+    }
+
+    fn hex(&mut self, len: usize, name: &'static str) -> Result<u64, DecodeError> {
+        let field = self.take(len, name)?;
+        if !field.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(DecodeError::InvalidField(name));
+        }
+        u64::from_str_radix(field, 16).map_err(|_| DecodeError::InvalidField(name))
+    }
+
+    fn time(&mut self) -> Result<u64, DecodeError> {
+        let time = self.take(TIME_LEN, "time")?;
+        if !time.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(DecodeError::InvalidField("time"));
+        }
+        time.parse().map_err(|_| DecodeError::InvalidField("time"))
+    }
+
+    /// An optional confirmation tag, the last field of its frame.
+    fn tag(&mut self) -> Result<Option<String>, DecodeError> {
+        if self.rest().is_empty() {
+            return Ok(None);
+        }
+        let tag = self.take(TAG_LEN, "tag")?.to_string();
+        self.end()?;
+        Ok(Some(tag))
+    }
+
+    /// Check that nothing follows the last field.
+    fn end(&self) -> Result<(), DecodeError> {
+        if self.pos < self.input.len() {
+            return Err(DecodeError::TooLong {
+                expected: self.pos,
+                found: self.input.len(),
+            });
+        }
+        Ok(())
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+}
@@ -0,0 +1,202 @@
+use crate::protocol::{self, Frame, MessageId};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the rest of a split message, and for how many at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReassemblyPolicy {
+    /// A message is given up on when no fragment of it arrived for this long.
+    pub timeout: Duration,
+    /// Messages from one sender put together at once; the one heard from longest ago
+    /// is given up on first.
+    pub per_sender: usize,
+    /// Messages put together at once from all senders, given up on the same way.
+    pub capacity: usize,
+}
+
+impl Default for ReassemblyPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(120),
+            per_sender: 4,
+            capacity: 32,
+        }
+    }
+}
+
+/// How far a split message has come in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub sender: String,
+    /// Us, or the channel it was sent to
+    pub recipient: String,
+    pub id: MessageId,
+    pub received: usize,
+    pub count: usize,
+}
+
+/// What [`Reassembler::insert`] made of a fragment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Assembly {
+    /// Fragments are still missing, the ones in `received` (a bit each) are here.
+    /// `ack` is set when none after this one is missing: the sender has likely sent
+    /// all it will for now and should hear which arrived.
+    Incomplete { received: u64, ack: bool },
+    /// That was the last one missing: the data frame they add up to.
+    Complete(Frame),
+}
+
+struct Partial {
+    recipient: String,
+    chunks: Vec<Option<String>>,
+    updated: Instant,
+}
+
+impl Partial {
+    fn received(&self) -> u64 {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| chunk.is_some())
+            .fold(0, |mask, (index, _)| mask | 1 << index)
+    }
+}
+
+/// Fragments of messages still coming in, by sender and message ID.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<(String, MessageId), Partial>,
+    policy: ReassemblyPolicy,
+}
+
+impl Reassembler {
+    pub fn policy(&self) -> ReassemblyPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: ReassemblyPolicy) {
+        self.policy = policy;
+    }
+
+    /// Add `fragment`, received at `now`. Returns `None` for anything but a fragment,
+    /// or one that disagrees with the others about how many there are.
+    pub fn insert(&mut self, fragment: &Frame, now: Instant) -> Option<Assembly> {
+        let Frame::Fragment {
+            id,
+            ttl,
+            hops,
+            index,
+            count,
+            recipient,
+            sender,
+            time,
+            chunk,
+            ..
+        } = fragment
+        else {
+            return None;
+        };
+        self.expire(now);
+        let key = (sender.clone(), *id);
+        if !self.partial.contains_key(&key) {
+            self.make_room(sender);
+        }
+        let partial = self.partial.entry(key.clone()).or_insert_with(|| Partial {
+            recipient: recipient.clone(),
+            chunks: vec![None; usize::from(*count)],
+            updated: now,
+        });
+        if partial.chunks.len() != usize::from(*count) {
+            return None;
+        }
+        let index = usize::from(*index);
+        partial.chunks[index] = Some(chunk.clone());
+        partial.updated = now;
+        if partial.chunks.iter().any(Option::is_none) {
+            return Some(Assembly::Incomplete {
+                received: partial.received(),
+                ack: partial.chunks[index + 1..].iter().all(Option::is_some),
+            });
+        }
+        let partial = self.partial.remove(&key)?;
+        let escaped: String = partial.chunks.into_iter().flatten().collect();
+        Some(Assembly::Complete(Frame::Data {
+            id: Some(*id),
+            ttl: *ttl,
+            hops: *hops,
+            next: None,
+            recipient: recipient.clone(),
+            sender: sender.clone(),
+            time: *time,
+            body: protocol::unescape_body(&escaped),
+        }))
+    }
+
+    /// Messages partly received and not timed out by `now`.
+    pub fn in_progress(&self, now: Instant) -> Vec<Progress> {
+        self.partial
+            .iter()
+            .filter(|(_, partial)| {
+                now.saturating_duration_since(partial.updated) <= self.policy.timeout
+            })
+            .map(|((sender, id), partial)| Progress {
+                sender: sender.clone(),
+                recipient: partial.recipient.clone(),
+                id: *id,
+                received: partial.received().count_ones() as usize,
+                count: partial.chunks.len(),
+            })
+            .collect()
+    }
+
+    /// Give up on the messages heard from longest ago until another from `sender` fits
+    /// within the limits, so a flood of first fragments can't take up all memory.
+    fn make_room(&mut self, sender: &str) {
+        loop {
+            let from_sender = self
+                .partial
+                .keys()
+                .filter(|(from, _)| from == sender)
+                .count();
+            let oldest = if from_sender >= self.policy.per_sender.max(1) {
+                self.oldest(|from| from == sender)
+            } else if self.partial.len() >= self.policy.capacity.max(1) {
+                self.oldest(|_| true)
+            } else {
+                return;
+            };
+            let Some((from, id)) = oldest else {
+                return;
+            };
+            eprintln!(
+                "Gave up on message {:08X} from {}: too many coming in",
+                id, from
+            );
+            self.partial.remove(&(from, id));
+        }
+    }
+
+    /// The message from a sender matching `from` that a fragment arrived for longest ago.
+    fn oldest(&self, from: impl Fn(&str) -> bool) -> Option<(String, MessageId)> {
+        self.partial
+            .iter()
+            .filter(|((sender, _), _)| from(sender))
+            .min_by_key(|(_, partial)| partial.updated)
+            .map(|(key, _)| key.clone())
+    }
+
+    /// Drop messages no fragment arrived for within the timeout.
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.policy.timeout;
+        self.partial.retain(|(sender, id), partial| {
+            let alive = now.saturating_duration_since(partial.updated) <= timeout;
+            if !alive {
+                eprintln!(
+                    "Gave up on message {:08X} from {}: fragments missing",
+                    id, sender
+                );
+            }
+            alive
+        });
+    }
+}
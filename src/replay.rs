@@ -0,0 +1,232 @@
+use crate::protocol::Frame;
+use crate::storage;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// How far behind a sender's newest frame an older one may still arrive, unless set
+/// otherwise: as long as relays hold messages by default.
+pub const DEFAULT_WINDOW_SECS: u64 = 24 * 3600;
+/// Shortest time between two saves, as a busy mesh changes the windows every frame.
+const SAVE_INTERVAL_SECS: u64 = 60;
+
+/// Remembers which data and confirmation frames each sender has already had accepted,
+/// so a captured packet played back later is dropped instead of shown or relayed again.
+///
+/// Per sender we keep the newest timestamp seen and a digest of every frame within the
+/// window of it. Anything older than the window is refused outright. The newest
+/// timestamp never runs ahead of our own clock, so a forged frame dated in the future
+/// can't push a sender's genuine traffic out of the window.
+///
+/// Only frames whose seal or tag checked out are recorded, so nobody without the key
+/// can move a window. Changes are saved at most once a minute, so whoever owns the
+/// guard has to [`flush`](Self::flush) it before the process exits.
+pub struct ReplayGuard {
+    windows: HashMap<String, Window>,
+    window_secs: u64,
+    path: Option<PathBuf>,
+    /// When the windows were last saved, and whether they changed since
+    saved_at: u64,
+    unsaved: bool,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self {
+            windows: HashMap::new(),
+            window_secs: DEFAULT_WINDOW_SECS,
+            path: None,
+            saved_at: 0,
+            unsaved: false,
+        }
+    }
+}
+
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct Window {
+    latest: u64,
+    /// `(time, digest)` of accepted frames
+    seen: Vec<(u64, u64)>,
+}
+
+/// Why [`ReplayGuard::check`] refused a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replay {
+    /// The same frame was accepted before.
+    Duplicate,
+    /// Too far behind the sender's newest frame to tell whether it was seen.
+    Stale,
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Replay::Duplicate => write!(f, "already received"),
+            Replay::Stale => write!(f, "older than the replay window"),
+        }
+    }
+}
+
+impl std::error::Error for Replay {}
+
+impl ReplayGuard {
+    /// Load the windows saved at `path` and save them there as they change.
+    pub fn persist(&mut self, path: PathBuf) {
+        self.windows = storage::load(&path).unwrap_or_default();
+        self.path = Some(path);
+    }
+
+    /// Accept frames up to `secs` behind a sender's newest one.
+    ///
+    /// Retried and held messages keep the time they were first sent, so this has to
+    /// cover how long they may still be delivered.
+    pub fn set_window(&mut self, secs: u64) {
+        self.window_secs = secs;
+    }
+
+    /// Say why `frame` (whose wire form is `payload`) is a replay, if it is one.
+    ///
+    /// Announcements, beacons and route discovery frames aren't tracked and always pass.
+    pub fn check(&self, frame: &Frame, payload: &str) -> Result<(), Replay> {
+        if let Frame::Announce { .. }
+        | Frame::Beacon { .. }
+        | Frame::RouteRequest { .. }
+        | Frame::RouteReply { .. } = frame
+        {
+            return Ok(());
+        }
+        let Some(window) = self.windows.get(frame.sender()) else {
+            return Ok(());
+        };
+        let time = frame.time();
+        if time + self.window_secs < window.latest {
+            return Err(Replay::Stale);
+        }
+        if window.seen.contains(&(time, digest(payload))) {
+            return Err(Replay::Duplicate);
+        }
+        Ok(())
+    }
+
+    /// Remember that `sender` had the frame sent at `time`, whose wire form is
+    /// `payload`, accepted. Call this only once the frame proved to be from `sender`.
+    pub fn record(&mut self, sender: &str, time: u64, payload: &str, now: u64) {
+        let window = self.windows.entry(sender.to_string()).or_default();
+        let entry = (time, digest(payload));
+        if window.seen.contains(&entry) {
+            return;
+        }
+        window.seen.push(entry);
+        window.latest = window.latest.max(time.min(now));
+        let (latest, window_secs) = (window.latest, self.window_secs);
+        window.seen.retain(|(time, _)| time + window_secs >= latest);
+        self.unsaved = true;
+        if now >= self.saved_at + SAVE_INTERVAL_SECS {
+            self.save(now);
+        }
+    }
+
+    /// Save changes not saved yet.
+    pub fn flush(&mut self, now: u64) {
+        if self.unsaved {
+            self.save(now);
+        }
+    }
+
+    fn save(&mut self, now: u64) {
+        if let Some(path) = &self.path {
+            if let Err(err) = storage::save(path, &self.windows) {
+                eprintln!("Failed to save replay state: {}", err);
+            }
+        }
+        self.saved_at = now;
+        self.unsaved = false;
+    }
+}
+
+fn digest(payload: &str) -> u64 {
+    let hash = Sha256::digest(payload.as_bytes());
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+
+    #[test]
+    fn replayed_frame_is_refused_after_restart() {
+        let path =
+            std::env::temp_dir().join(format!("lora_mesh_replay_{}.ron", std::process::id()));
+        let data = |time| Frame::Data {
+            id: Some(7),
+            ttl: protocol::DEFAULT_TTL,
+            hops: 0,
+            next: None,
+            recipient: "002E0051044A7EE1000026BF".to_string(),
+            sender: "002E0051044A7EE1000026C0".to_string(),
+            time,
+            body: "hello".to_string(),
+        };
+        let now = 1_700_000_000;
+        let frame = data(now);
+        let payload = protocol::encode(&frame);
+
+        let mut guard = ReplayGuard::default();
+        guard.persist(path.clone());
+        assert_eq!(guard.check(&frame, &payload), Ok(()));
+        // Nothing counts until it is recorded, once the frame proved genuine
+        assert_eq!(guard.check(&frame, &payload), Ok(()));
+        guard.record(frame.sender(), now, &payload, now);
+        assert_eq!(guard.check(&frame, &payload), Err(Replay::Duplicate));
+
+        let mut restarted = ReplayGuard::default();
+        restarted.persist(path.clone());
+        assert_eq!(restarted.check(&frame, &payload), Err(Replay::Duplicate));
+        let later = data(now + 2 * 86400);
+        let later_payload = protocol::encode(&later);
+        assert_eq!(restarted.check(&later, &later_payload), Ok(()));
+        restarted.record(
+            later.sender(),
+            now + 2 * 86400,
+            &later_payload,
+            now + 2 * 86400,
+        );
+        assert_eq!(restarted.check(&frame, &payload), Err(Replay::Stale));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn frame_recorded_between_saves_is_refused_once_flushed() {
+        let path = std::env::temp_dir().join(format!("lora_mesh_flush_{}.ron", std::process::id()));
+        let data = |id| Frame::Data {
+            id: Some(id),
+            ttl: protocol::DEFAULT_TTL,
+            hops: 0,
+            next: None,
+            recipient: "002E0051044A7EE1000026BF".to_string(),
+            sender: "002E0051044A7EE1000026C0".to_string(),
+            time: 1_700_000_000,
+            body: "hello".to_string(),
+        };
+        let now = 1_700_000_000;
+        let mut guard = ReplayGuard::default();
+        guard.persist(path.clone());
+        for id in [1, 2] {
+            let frame = data(id);
+            guard.record(frame.sender(), now, &protocol::encode(&frame), now + 1);
+        }
+        // The second came too soon after the first to be saved with it
+        guard.flush(now + 1);
+
+        let mut restarted = ReplayGuard::default();
+        restarted.persist(path.clone());
+        let second = data(2);
+        assert_eq!(
+            restarted.check(&second, &protocol::encode(&second)),
+            Err(Replay::Duplicate)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
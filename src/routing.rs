@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long learned routes are trusted and how often one is looked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoutePolicy {
+    /// A route not confirmed by fresh traffic for this long is dropped.
+    pub lifetime: Duration,
+    /// Least time between two route requests for the same station.
+    pub discovery_interval: Duration,
+}
+
+impl Default for RoutePolicy {
+    fn default() -> Self {
+        Self {
+            lifetime: Duration::from_secs(300),
+            discovery_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The way to one station.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// The neighbor to hand frames for the station to; the station itself if in range
+    pub next_hop: String,
+    /// Relays between us and the station
+    pub hops: u8,
+    pub learned: Instant,
+}
+
+/// Next hops by destination UID, learned from route requests and replies and from
+/// stations heard directly.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    routes: HashMap<String, Route>,
+    /// When we last asked for a route to each station
+    requested: HashMap<String, Instant>,
+    policy: RoutePolicy,
+}
+
+impl RoutingTable {
+    pub fn policy(&self) -> RoutePolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: RoutePolicy) {
+        self.policy = policy;
+    }
+
+    /// The route to `destination`, unless there is none or it has expired.
+    pub fn get(&self, destination: &str, now: Instant) -> Option<&Route> {
+        self.routes
+            .get(destination)
+            .filter(|route| now.saturating_duration_since(route.learned) <= self.policy.lifetime)
+    }
+
+    /// Note that `destination` is `hops` relays away through `next_hop`. A known route
+    /// is only replaced by a shorter one, or refreshed over the same neighbor.
+    pub fn learn(&mut self, destination: &str, next_hop: &str, hops: u8, now: Instant) {
+        if let Some(route) = self.get(destination, now) {
+            if route.next_hop != next_hop && route.hops < hops {
+                return;
+            }
+        }
+        self.routes.insert(
+            destination.to_string(),
+            Route {
+                next_hop: next_hop.to_string(),
+                hops,
+                learned: now,
+            },
+        );
+        self.requested.remove(destination);
+    }
+
+    /// Drop the route to `destination`, e.g. because traffic over it went unanswered.
+    pub fn forget(&mut self, destination: &str) {
+        self.routes.remove(destination);
+    }
+
+    /// Whether it is time to ask for a route to `destination`, noting that we will.
+    pub fn should_request(&mut self, destination: &str, now: Instant) -> bool {
+        let due = self.requested.get(destination).map_or(true, |last| {
+            now.saturating_duration_since(*last) >= self.policy.discovery_interval
+        });
+        if due {
+            self.requested.insert(destination.to_string(), now);
+        }
+        due
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Route)> {
+        self.routes.iter()
+    }
+}
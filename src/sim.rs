@@ -0,0 +1,263 @@
+use crate::emulator::AtModule;
+use crate::node::Node;
+use crate::outbox::RetryPolicy;
+use crate::transport::Transport;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Radio conditions applied to every pair of stations within range.
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// Stations further apart than this can't hear each other.
+    pub range: f64,
+    pub latency: Duration,
+    /// Chance (0.0 to 1.0) that a single reception is lost.
+    pub loss: f64,
+    /// Seed for the loss generator so runs are repeatable.
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            range: 100.0,
+            latency: Duration::from_millis(10),
+            loss: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+/// Conditions on one directed link, overriding the range based default.
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub loss: f64,
+    pub latency: Duration,
+}
+
+struct Radio {
+    module: AtModule,
+    position: (f64, f64),
+    inbox: VecDeque<(Instant, String)>,
+}
+
+/// The shared medium all simulated radios transmit into.
+struct Air {
+    config: SimConfig,
+    radios: Vec<Radio>,
+    links: HashMap<(usize, usize), Option<Link>>,
+    rng: u64,
+    transmissions: usize,
+    rejected: usize,
+}
+
+impl Air {
+    fn distance(&self, from: usize, to: usize) -> f64 {
+        let (ax, ay) = self.radios[from].position;
+        let (bx, by) = self.radios[to].position;
+        ((ax - bx).powi(2) + (ay - by).powi(2)).sqrt()
+    }
+
+    fn link(&self, from: usize, to: usize) -> Option<Link> {
+        if let Some(link) = self.links.get(&(from, to)) {
+            return link.clone();
+        }
+        if self.distance(from, to) <= self.config.range {
+            Some(Link {
+                loss: self.config.loss,
+                latency: self.config.latency,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// xorshift64, good enough to decide which packets get lost.
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn transmit(&mut self, from: usize, data: &str) {
+        self.transmissions += 1;
+        let now = Instant::now();
+        for to in 0..self.radios.len() {
+            if to == from {
+                continue;
+            }
+            let Some(link) = self.link(from, to) else {
+                continue;
+            };
+            if self.random() < link.loss {
+                continue;
+            }
+            // Signal falls off with distance across the usable range
+            let fraction = (self.distance(from, to) / self.config.range).min(1.0);
+            let rssi = -40 - (80.0 * fraction) as i32;
+            let snr = 12 - (20.0 * fraction) as i32;
+            let address = self.radios[from].module.address();
+            let line = self.radios[to].module.receive(address, data, rssi, snr);
+            self.radios[to].inbox.push_back((now + link.latency, line));
+        }
+    }
+
+    fn reply(&mut self, radio: usize, line: String) {
+        self.radios[radio].inbox.push_back((Instant::now(), line));
+    }
+}
+
+/// One station's emulated module, attached to the simulated air.
+pub struct SimRadio {
+    index: usize,
+    air: Arc<Mutex<Air>>,
+}
+
+impl Transport for SimRadio {
+    fn send_line(&mut self, line: &str) -> io::Result<()> {
+        let mut air = self.air.lock().unwrap();
+        let response = air.radios[self.index].module.command(line);
+        if let Some(data) = &response.transmit {
+            air.transmit(self.index, data);
+        }
+        if response.reply.starts_with("+ERR") {
+            air.rejected += 1;
+        }
+        air.reply(self.index, response.reply);
+        Ok(())
+    }
+
+    fn recv_line(&mut self, timeout: Duration) -> io::Result<Option<String>> {
+        let start_time = Instant::now();
+        loop {
+            {
+                let mut air = self.air.lock().unwrap();
+                let inbox = &mut air.radios[self.index].inbox;
+                if let Some(index) = inbox.iter().position(|(at, _)| *at <= Instant::now()) {
+                    return Ok(inbox.remove(index).map(|(_, line)| line));
+                }
+            }
+            if start_time.elapsed() >= timeout {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A set of [`Node`]s running over simulated radios in one process.
+pub struct Simulator {
+    air: Arc<Mutex<Air>>,
+    nodes: Vec<Node>,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Self {
+        let rng = config.seed.max(1);
+        Self {
+            air: Arc::new(Mutex::new(Air {
+                config,
+                radios: Vec::new(),
+                links: HashMap::new(),
+                rng,
+                transmissions: 0,
+                rejected: 0,
+            })),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Place a new station at `position` and return its index.
+    pub fn add_node(&mut self, position: (f64, f64)) -> usize {
+        let index = self.nodes.len();
+        let uid = format!("{:024X}", 0x5100 + index);
+        self.air.lock().unwrap().radios.push(Radio {
+            module: AtModule::new(uid.clone()),
+            position,
+            inbox: VecDeque::new(),
+        });
+        let radio: Box<dyn Transport> = Box::new(SimRadio {
+            index,
+            air: self.air.clone(),
+        });
+        let mut node = Node::new(
+            uid,
+            Arc::new(Mutex::new(radio)),
+            Arc::new(Mutex::new(HashMap::new())),
+        );
+        // A simulated module takes the next frame right away; one still goes out per step
+        node.set_retry_policy(RetryPolicy {
+            frame_gap: Duration::ZERO,
+            ..Default::default()
+        });
+        self.nodes.push(node);
+        index
+    }
+
+    /// Override the link between `a` and `b` in both directions. `None` cuts it.
+    pub fn set_link(&mut self, a: usize, b: usize, link: Option<Link>) {
+        let mut air = self.air.lock().unwrap();
+        air.links.insert((a, b), link.clone());
+        air.links.insert((b, a), link);
+    }
+
+    pub fn node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    pub fn node_mut(&mut self, index: usize) -> &mut Node {
+        &mut self.nodes[index]
+    }
+
+    pub fn uid(&self, index: usize) -> String {
+        self.nodes[index].userid().to_string()
+    }
+
+    /// Number of `AT+SEND` transmissions made by all stations so far.
+    pub fn transmissions(&self) -> usize {
+        self.air.lock().unwrap().transmissions
+    }
+
+    /// Number of commands any module answered with `+ERR=`.
+    pub fn rejected_commands(&self) -> usize {
+        self.air.lock().unwrap().rejected
+    }
+
+    /// Let every node handle whatever its radio has received by now.
+    pub fn step(&mut self) {
+        for node in &mut self.nodes {
+            while let Ok(true) = node.poll(Duration::ZERO) {}
+            node.flush_outbox();
+        }
+    }
+
+    /// Keep stepping for `duration`.
+    pub fn run_for(&mut self, duration: Duration) {
+        let start_time = Instant::now();
+        while start_time.elapsed() < duration {
+            self.step();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Keep stepping until `done` holds or `timeout` passes. Returns whether `done` held.
+    pub fn run_until(&mut self, timeout: Duration, mut done: impl FnMut(&Self) -> bool) -> bool {
+        let start_time = Instant::now();
+        while start_time.elapsed() < timeout {
+            self.step();
+            if done(self) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        false
+    }
+}
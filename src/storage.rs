@@ -0,0 +1,37 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Same id `main` passes to `eframe::run_native`, so our files sit next to the app state.
+const APP_ID: &str = "lora_mesh";
+
+/// Where the app keeps its files, e.g. `~/.local/share/lora_mesh` on Linux.
+pub fn data_dir() -> Option<PathBuf> {
+    eframe::storage_dir(APP_ID)
+}
+
+/// The path of `name` inside [`data_dir`], creating the directory if needed.
+pub fn data_file(name: &str) -> Option<PathBuf> {
+    let dir = data_dir()?;
+    fs::create_dir_all(&dir).ok()?;
+    Some(dir.join(name))
+}
+
+/// Read a RON file, or `None` if it is missing or unreadable.
+pub fn load<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let text = fs::read_to_string(path).ok()?;
+    ron::from_str(&text)
+        .map_err(|err| eprintln!("Ignoring unreadable {}: {}", path.display(), err))
+        .ok()
+}
+
+/// Write `value` as RON, replacing the file in one step so a crash can't truncate it.
+pub fn save<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let text = ron::ser::to_string_pretty(value, Default::default())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, text)?;
+    fs::rename(&temp, path)
+}
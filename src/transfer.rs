@@ -0,0 +1,261 @@
+use crate::node::{DeliveryState, Message};
+use crate::protocol::MessageId;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Bytes of a file carried in one message. In base64 and sealed, a chunk still fits
+/// the fragments of one message, with room for the header.
+pub const CHUNK_LEN: usize = 2048;
+/// Largest file we send. Every chunk is kept in the message history like any message.
+pub const MAX_FILE_LEN: usize = 32 * CHUNK_LEN;
+/// Longest file name sent along, in characters.
+const NAME_LEN: usize = 64;
+/// Starts the body of a message carrying a chunk; nobody types a control character.
+const CHUNK_MARKER: &str = "\u{1}FILE ";
+
+/// What every chunk says about the file it is part of.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileInfo {
+    /// File name without any directory
+    pub name: String,
+    pub size: u64,
+    /// SHA-256 of the whole file, in hex
+    pub checksum: String,
+    /// Chunks the file is sent in
+    pub count: u32,
+}
+
+/// One piece of a file, sent as the body of a message so it is sealed, split,
+/// confirmed and retried like any other.
+///
+/// The body is `\x01FILE checksum(64) index count size name`, a newline, then the
+/// piece of the file in base64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub file: FileInfo,
+    pub index: u32,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn encode(&self) -> String {
+        let FileInfo {
+            name,
+            size,
+            checksum,
+            count,
+        } = &self.file;
+        format!(
+            "{CHUNK_MARKER}{checksum} {} {count} {size} {name}\n{}",
+            self.index,
+            STANDARD_NO_PAD.encode(&self.data)
+        )
+    }
+
+    /// The chunk a message body carries, or `None` if it is ordinary text.
+    pub fn decode(body: &str) -> Option<Self> {
+        let (file, index) = header(body)?;
+        let (_, data) = body.split_once('\n')?;
+        let data = STANDARD_NO_PAD.decode(data).ok()?;
+        Some(Self { file, index, data })
+    }
+}
+
+/// The file and chunk index a message body carries, without decoding the chunk, or
+/// `None` if it is ordinary text.
+pub fn header(body: &str) -> Option<(FileInfo, u32)> {
+    let (line, _) = body.strip_prefix(CHUNK_MARKER)?.split_once('\n')?;
+    let mut fields = line.splitn(5, ' ');
+    let checksum = fields.next()?;
+    let index: u32 = fields.next()?.parse().ok()?;
+    let count: u32 = fields.next()?.parse().ok()?;
+    let size: u64 = fields.next()?.parse().ok()?;
+    let name = fields.next()?;
+    // The count follows from the size, so neither can make us set aside room for
+    // more chunks than a file we'd send ever has
+    let valid = checksum.len() == 64
+        && checksum.bytes().all(|byte| byte.is_ascii_hexdigit())
+        && size <= MAX_FILE_LEN as u64
+        && u64::from(count) == ((size + CHUNK_LEN as u64 - 1) / CHUNK_LEN as u64).max(1)
+        && index < count
+        && name == clean_name(name);
+    valid.then(|| {
+        let file = FileInfo {
+            name: name.to_string(),
+            size,
+            checksum: checksum.to_ascii_lowercase(),
+            count,
+        };
+        (file, index)
+    })
+}
+
+/// SHA-256 of `bytes` in hex.
+pub fn checksum(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Split `bytes`, the contents of file `name`, into chunks to send a message each.
+pub fn split(name: &str, bytes: &[u8]) -> Result<Vec<Chunk>, TransferError> {
+    if bytes.len() > MAX_FILE_LEN {
+        return Err(TransferError::TooLarge(bytes.len()));
+    }
+    let mut pieces: Vec<&[u8]> = bytes.chunks(CHUNK_LEN).collect();
+    if pieces.is_empty() {
+        // An empty file still needs a chunk to say it exists
+        pieces.push(&[]);
+    }
+    let file = FileInfo {
+        name: clean_name(name),
+        size: bytes.len() as u64,
+        checksum: checksum(bytes),
+        count: pieces.len() as u32,
+    };
+    Ok(pieces
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| Chunk {
+            file: file.clone(),
+            index: index as u32,
+            data: data.to_vec(),
+        })
+        .collect())
+}
+
+/// The last component of `name`, without control characters and cut short, so it
+/// can neither break the header nor point a save outside the chosen directory.
+fn clean_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(NAME_LEN)
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "file".to_string(),
+        _ => name,
+    }
+}
+
+/// A file in a conversation, as far as its chunk messages there go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    pub sender: String,
+    pub file: FileInfo,
+    /// Send time of the first chunk
+    pub time: u64,
+    /// Chunks we have of a file we receive, or confirmed ones of a file we send
+    pub done: u32,
+    /// Our chunks that ran out of retries; retrying them resumes the transfer
+    pub failed: Vec<MessageId>,
+}
+
+impl Transfer {
+    pub fn complete(&self) -> bool {
+        self.done == self.file.count
+    }
+}
+
+/// The files in `conversation`, in the order they started. `own` is our UID.
+pub fn transfers(conversation: &[Message], own: &str) -> Vec<Transfer> {
+    let mut transfers: Vec<Transfer> = Vec::new();
+    let mut done: HashMap<(String, String), HashSet<u32>> = HashMap::new();
+    for message in conversation {
+        let Some((file, index)) = header(&message.data) else {
+            continue;
+        };
+        let key = (message.sender.clone(), file.checksum.clone());
+        let position = transfers
+            .iter()
+            .position(|transfer| transfer.sender == message.sender && transfer.file == file);
+        let transfer = match position {
+            Some(position) => &mut transfers[position],
+            None => {
+                transfers.push(Transfer {
+                    sender: message.sender.clone(),
+                    file,
+                    time: message.time,
+                    done: 0,
+                    failed: Vec::new(),
+                });
+                transfers.last_mut().unwrap()
+            }
+        };
+        let ours = message.sender == own;
+        match message.state {
+            DeliveryState::Failed if ours => transfer.failed.extend(message.id),
+            DeliveryState::Delivered => {
+                done.entry(key).or_default().insert(index);
+            }
+            _ if !ours => {
+                done.entry(key).or_default().insert(index);
+            }
+            _ => {}
+        }
+    }
+    for transfer in &mut transfers {
+        let key = (transfer.sender.clone(), transfer.file.checksum.clone());
+        transfer.done = done.get(&key).map_or(0, |indices| indices.len() as u32);
+    }
+    transfers
+}
+
+/// Put `file` together from the chunks `sender` sent in `conversation`, checking it
+/// against its checksum.
+pub fn assemble(
+    conversation: &[Message],
+    sender: &str,
+    file: &FileInfo,
+) -> Result<Vec<u8>, TransferError> {
+    let mut pieces: Vec<Option<Vec<u8>>> = vec![None; file.count as usize];
+    for message in conversation
+        .iter()
+        .filter(|message| message.sender == sender)
+    {
+        match Chunk::decode(&message.data) {
+            Some(chunk) if chunk.file == *file => pieces[chunk.index as usize] = Some(chunk.data),
+            _ => {}
+        }
+    }
+    let bytes = pieces
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .ok_or(TransferError::Incomplete)?
+        .concat();
+    if bytes.len() as u64 != file.size || checksum(&bytes) != file.checksum {
+        return Err(TransferError::Checksum);
+    }
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferError {
+    /// The file has this many bytes, more than [`MAX_FILE_LEN`].
+    TooLarge(usize),
+    /// Chunks of the file haven't arrived yet.
+    Incomplete,
+    /// The chunks don't add up to the file that was sent.
+    Checksum,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::TooLarge(len) => write!(
+                f,
+                "file is {} bytes, at most {} can be sent",
+                len, MAX_FILE_LEN
+            ),
+            TransferError::Incomplete => write!(f, "parts of the file are still missing"),
+            TransferError::Checksum => write!(f, "checksum mismatch, the file arrived damaged"),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
@@ -0,0 +1,140 @@
+use serialport::{self, available_ports, SerialPort};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A line-oriented link to a LoRa module speaking the RYLR AT dialect.
+///
+/// Lines are passed without their `\r\n` terminator; the transport adds and strips it.
+pub trait Transport: Send {
+    /// Write one command line to the module.
+    fn send_line(&mut self, line: &str) -> io::Result<()>;
+
+    /// Wait up to `timeout` for a complete line from the module.
+    ///
+    /// Returns `Ok(None)` if nothing complete arrived in time, and an error of kind
+    /// [`io::ErrorKind::NotConnected`] when the link is down.
+    fn recv_line(&mut self, timeout: Duration) -> io::Result<Option<String>>;
+
+    /// Drop the current link, if any, and try to establish it again.
+    fn reconnect(&mut self) -> io::Result<()>;
+}
+
+/// The transport handle shared between the UI and the receive thread.
+pub type SharedTransport = Arc<Mutex<Box<dyn Transport>>>;
+
+/// A module attached over USB serial (the Arduino Transceiver sketch).
+#[derive(Default)]
+pub struct SerialTransport {
+    port: Option<Box<dyn SerialPort>>,
+    buffer: String,
+}
+
+impl SerialTransport {
+    /// Open the first attached Arduino, or return a disconnected transport.
+    pub fn open() -> Self {
+        Self {
+            port: open_serial_port(),
+            buffer: String::new(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.port.is_some()
+    }
+
+    fn take_line(&mut self) -> Option<String> {
+        let end = self.buffer.find('\n')?;
+        let line: String = self.buffer.drain(..=end).collect();
+        Some(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+impl Transport for SerialTransport {
+    fn send_line(&mut self, line: &str) -> io::Result<()> {
+        let port = self.port.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+        let result = port.write_all(format!("{}\r\n", line).as_bytes());
+        if let Err(err) = &result {
+            if err.kind() != io::ErrorKind::TimedOut {
+                self.port = None;
+            }
+        }
+        result
+    }
+
+    fn recv_line(&mut self, timeout: Duration) -> io::Result<Option<String>> {
+        let start_time = Instant::now();
+        let mut serial_buf: Vec<u8> = vec![0; 300];
+        loop {
+            if let Some(line) = self.take_line() {
+                return Ok(Some(line));
+            }
+            let port = self.port.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+            match port.read(&mut serial_buf) {
+                Ok(count) => {
+                    self.buffer
+                        .push_str(&String::from_utf8_lossy(&serial_buf[..count]));
+                    continue;
+                }
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+                Err(err) => {
+                    // The board was most likely unplugged
+                    self.port = None;
+                    return Err(err);
+                }
+            }
+            if start_time.elapsed() >= timeout {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        self.port = None;
+        self.buffer.clear();
+        self.port = open_serial_port();
+        if self.port.is_some() {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::NotConnected.into())
+        }
+    }
+}
+
+fn open_serial_port() -> Option<Box<dyn SerialPort>> {
+    let port_name = (|| -> Result<String, Box<dyn std::error::Error>> {
+        let ports = available_ports()?;
+        for p in ports {
+            match p.port_type {
+                serialport::SerialPortType::UsbPort(usb_info) => {
+                    // Many Arduinos have a VID of 0x2341 and PIDs of 0x0042 or 0x0043
+                    if usb_info.vid == 0x2341 && (usb_info.pid == 0x0042 || usb_info.pid == 0x0043)
+                    {
+                        return Ok(p.port_name);
+                    }
+                }
+                serialport::SerialPortType::PciPort => {
+                    eprintln!("Haven't implemented handling Pci Devices")
+                }
+                serialport::SerialPortType::BluetoothPort => {
+                    eprintln!("Haven't implemented handling Bluetooth Devices")
+                }
+                serialport::SerialPortType::Unknown => {
+                    eprintln!("Haven't implemented handling unknown devices")
+                }
+            }
+        }
+        Err("No suitable port found".into())
+    })()
+    .ok()?;
+
+    serialport::new(port_name, 9600)
+        .timeout(Duration::from_millis(10))
+        .open()
+        .map_err(|e| {
+            eprintln!("Failed to open serial port: {}", e);
+        })
+        .ok()
+}